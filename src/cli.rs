@@ -87,6 +87,7 @@ Some common freighter commands are (see all commands with --list):
     rustup    Sync the rustup files from the upstream to local, cloud or registry
     channel   Sync the toolchain files from the upstream to local, cloud or registry
     server    Start git and file http server 
+    token     Manage the api tokens used by cargo to publish crates
 
 See 'freighter help <command>' for more information on a specific command.\n"
         )
@@ -23,6 +23,9 @@ pub trait CloudStorage {
 
     /// this operation will upload all files in folder
     fn upload_folder(&self, folder: &str, bucket: &str) -> FreightResult;
+
+    /// delete a single file from target storage
+    fn delete_file(&self, s3_path: &str, bucket: &str) -> FreightResult;
 }
 
 // this method is used to handle 'upload' subcommand for upload all files to obs server
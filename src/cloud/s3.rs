@@ -54,4 +54,18 @@ impl CloudStorage for S3cmd {
         }
         Ok(())
     }
+
+    fn delete_file(&self, s3_path: &str, bucket: &str) -> FreightResult {
+        let s3_full_path = format!("s3://{}/{}", bucket, s3_path);
+        tracing::debug!("delete s3_full_path: {}", s3_full_path);
+        let status = Command::new("s3cmd")
+            .arg("del")
+            .arg(s3_full_path)
+            .status()
+            .expect("failed to execute s3cmd del");
+        if !status.success() {
+            return Err(FreighterError::code(status.code().unwrap()));
+        }
+        Ok(())
+    }
 }
@@ -49,6 +49,7 @@ use crate::cloud;
 use crate::cloud::s3::S3cmd;
 use crate::commands::command_prelude::*;
 use crate::config::Config;
+use crate::download::Downloader;
 use crate::errors::FreightResult;
 use crate::handler::channel::{sync_rust_toolchain, ChannelOptions};
 
@@ -130,6 +131,7 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
             .build()
             .unwrap(),
     );
+    opts.downloader = Some(Downloader::new(&opts.proxy, opts.config.download_threads)?);
 
     tracing::info!("Default ChannelOptions : {:#?}", opts);
 
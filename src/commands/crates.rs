@@ -16,6 +16,7 @@
 //!
 //!   - The crates index is a git repository, and **cargo** clone and update from [GitHub](https://github.com/rust-lang/crates.io-index).
 //!     - The clone use `bare` mode, more details in the [cargo guide](https://github.com/rust-lang/cargo/blob/6b6b0b486d73c03ed952591d880debec1d47c534/src/doc/src/guide/cargo-home.md#directories)
+//!   - The search index used by `cargo search` is rebuilt after the index is updated.
 //!   
 //! # download subcommand
 //!   sync crate file from upstream to local:
@@ -34,6 +35,20 @@
 //!   - __upload__: Whether to upload single file to s3 after download success.
 //!   - __bucket__: set the s3 bucket you want to upload files to, you must provide this param before upload.
 //!   - __delete-after-upload__: This optional parameter will be used to delete files after upload.
+//!   - __lockfile__: Only download the crates.io packages locked by the given `Cargo.lock` files,
+//!         the missing ones are downloaded on later runs with the same lockfiles.
+//!   - __root__: Only download the dependency closure of the given root crates like `tokio@1`,
+//!         it's resolved over the local index and merged with the crates of lockfiles.
+//!
+//! # prune subcommand
+//!
+//!   - Delete the downloaded crate files which are not kept by `skip_yanked`, `keep_latest` and
+//!     `published_after` of the crates config, or denied by the policy. Crates published to
+//!     this registry are never deleted.
+//!
+//!   Arguments:
+//!   - __bucket__: also delete the copies in the s3 bucket.
+//!   - __dry-run__: only print the files which would be deleted.
 //!
 //! # upload subcommand
 //!
@@ -49,16 +64,20 @@
 //!   - __bucket__: set the s3 bucket you want to upload files to, you must provide this param before upload.
 //!  
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use clap::{arg, ArgMatches};
 use rayon::ThreadPoolBuilder;
 
 use crate::commands::command_prelude::*;
-use crate::config::Config;
+use crate::config::{Config, RootCrate};
+use crate::download::Downloader;
 use crate::errors::FreightResult;
-use crate::handler::crates_file::{download, upload_to_s3, CratesOptions};
+use crate::handler::crates_file::{download, prune, upload_to_s3, CratesOptions};
 use crate::handler::index::{pull, CrateIndex};
+use crate::handler::policy::Policy;
+use crate::handler::retention::Retention;
 use crate::handler::DownloadMode;
 
 /// The __crates__ subcommand
@@ -85,6 +104,22 @@ pub fn cli() -> clap::Command {
             .arg(flag("upload", "upload every crate file after download"))
             .arg(arg!(-b --"bucket" <VALUE> "set the s3 bucket name you want to upload files"))
             .arg(flag("delete-after-upload", "this will delete file after upload"))
+            .arg(arg!(--"lockfile" <PATH> "only download the crates.io packages locked by the Cargo.lock files, 
+        this param can be changed in the configuration file or pass it here")
+                .num_args(1..)
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(PathBuf))
+            )
+            .arg(arg!(--"root" <CRATE> "only download the dependency closure of the root crates like tokio@1, 
+        this param can be changed in the configuration file or pass it here")
+                .num_args(1..)
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(RootCrate))
+            )
+        )
+        .subcommand(subcommand("prune")
+            .arg(arg!(-b --"bucket" <VALUE> "also delete the pruned files from the s3 bucket"))
+            .arg(flag("dry-run", "only print the files which would be deleted"))
         )
         .subcommand_required(true)
         .arg_required_else_help(true)
@@ -113,6 +148,18 @@ EXAMPLES
 
        freighter crates -t 32 download --init
 
+4. Download only the crates used by some projects:
+
+       freighter crates download --lockfile ./a/Cargo.lock ./b/Cargo.lock
+
+5. Download only the crates which tokio 1.x and serde could depend on:
+
+       freighter crates download --root tokio@1 serde
+
+6. Delete the downloaded versions which are not kept by the retention rules:
+
+       freighter crates prune --bucket crates
+
 \n")
 }
 
@@ -124,11 +171,16 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
 
     let opts = &mut CratesOptions {
         config: config.crates.to_owned(),
+        server: config.server.to_owned(),
+        policy: Arc::new(Policy::load(&config.crates.policy_path)?),
+        retention: Arc::new(Retention::new(&config.crates)?),
         proxy: config.proxy.to_owned(),
         index: CrateIndex::new(&config.crates.index_domain, config.index_path.to_owned()),
         no_progressbar: args.get_flag("no-progressbar"),
         crates_path: config.crates_path.to_owned(),
         log_path: config.log_path.to_owned(),
+        search_path: config.search_path.to_owned(),
+        owners_path: config.owners_path.to_owned(),
         ..Default::default()
     };
     let domain = args.get_one::<String>("domain").cloned();
@@ -143,6 +195,7 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
             .build()
             .unwrap(),
     );
+    opts.downloader = Some(Downloader::new(&opts.proxy, opts.config.download_threads)?);
 
     tracing::info!("CratesOptions info : {:#?}", opts);
 
@@ -158,6 +211,12 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
             opts.download_mode = DownloadMode::new(args.get_flag("init"), args.get_flag("fix"));
             opts.delete_after_upload = args.get_flag("delete-after-upload");
             opts.crates_name = args.get_one::<String>("name").cloned();
+            if let Some(lockfiles) = args.get_many::<PathBuf>("lockfile") {
+                opts.config.lockfiles = lockfiles.cloned().collect();
+            }
+            if let Some(roots) = args.get_many::<RootCrate>("root") {
+                opts.config.roots = roots.cloned().collect();
+            }
             if opts.upload {
                 if let Some(bucket_name) = args.get_one::<String>("bucket").cloned() {
                     opts.bucket_name = bucket_name
@@ -170,6 +229,12 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
             }
             download(opts)?
         }
+        Some(("prune", args)) => {
+            if let Some(bucket_name) = args.get_one::<String>("bucket").cloned() {
+                opts.bucket_name = bucket_name;
+            }
+            prune(opts, args.get_flag("dry-run"))?
+        }
         Some(("upload", args)) => {
             opts.bucket_name = args.get_one::<String>("bucket").cloned().unwrap();
             opts.crates_name = args.get_one::<String>("name").cloned();
@@ -15,6 +15,7 @@ pub mod command_prelude;
 pub mod crates;
 pub mod rustup_init;
 pub mod server;
+pub mod token;
 
 /// The builtin function is the entry point of commands mod. Each subcommand is a
 /// `clap::Command<'static>` type, and the `exec` function is logic entry.
@@ -30,6 +31,7 @@ pub fn builtin() -> Vec<App> {
         rustup_init::cli(),
         channel::cli(),
         server::cli(),
+        token::cli(),
     ]
 }
 
@@ -42,6 +44,7 @@ pub fn builtin_exec(cmd: &str) -> Option<fn(&mut Config, &ArgMatches) -> Freight
         "rustup" => rustup_init::exec,
         "channel" => channel::exec,
         "server" => server::exec,
+        "token" => token::exec,
         _ => return None,
     };
 
@@ -29,15 +29,13 @@
 //!   - __bucket__: set the s3 bucket you want to upload files to, you must provide this param before upload.
 //!   
 
-use std::sync::Arc;
-
 use clap::{arg, ArgMatches};
-use rayon::ThreadPoolBuilder;
 
 use crate::cloud::s3::S3cmd;
 use crate::cloud::CloudStorage;
 use crate::commands::command_prelude::*;
 use crate::config::Config;
+use crate::download::Downloader;
 use crate::errors::FreightResult;
 use crate::handler::rustup::{sync_rustup_init, RustUpOptions};
 
@@ -101,12 +99,7 @@ pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
         opts.config.download_threads = download_threads;
     };
 
-    opts.thread_pool = Arc::new(
-        ThreadPoolBuilder::new()
-            .num_threads(opts.config.download_threads)
-            .build()
-            .unwrap(),
-    );
+    opts.downloader = Some(Downloader::new(&opts.proxy, opts.config.download_threads)?);
 
     tracing::info!("RustUpOptions info : {:#?}", opts);
 
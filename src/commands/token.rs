@@ -0,0 +1,98 @@
+//! **token** subcommand focus on manage the api tokens used by cargo to access the
+//! write operations of the registry, like publish, yank and owner. The core
+//! function implemented in the `src/server/tokens`.
+//!
+//! # create subcommand
+//!   - create a new token for a user, the token will only be printed once, you can
+//!     save it in cargo with `cargo login --registry <name>`.
+//!
+//!   Arguments:
+//!   - __login__: the user the token belongs to, it's used as the owner of published crates.
+//!   - __name__: a description of where the token is used.
+//!
+//! # list subcommand
+//!   - list all tokens without the token value.
+//!
+//! # revoke subcommand
+//!   - revoke a token by the id shown in list subcommand.
+//!
+
+use clap::{arg, ArgMatches};
+
+use crate::commands::command_prelude::*;
+use crate::config::Config;
+use crate::errors::FreightResult;
+use crate::server::tokens::TokenStore;
+
+pub fn cli() -> clap::Command {
+    clap::Command::new("token")
+        .subcommand(subcommand("create")
+            .arg(arg!(-l --"login" <VALUE> "the user this token belongs to").required(true))
+            .arg(arg!(-n --"name" <VALUE> "a description of where the token is used").default_value(""))
+        )
+        .subcommand(subcommand("list"))
+        .subcommand(subcommand("revoke")
+            .arg(arg!(--"id" <VALUE> "the id of the token to revoke")
+                .value_parser(value_parser!(u32))
+                .required(true))
+        )
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .about("Manage the api tokens of the registry")
+        .help_template(
+            "\
+Manage the api tokens which cargo uses to publish, yank crates and edit crate owners.
+
+USAGE:
+    {usage}
+
+OPTIONS:
+{options}
+
+EXAMPLES
+1. Create a token for user alice
+
+       freighter token create --login alice --name ci
+
+2. Revoke the token with id 1
+
+       freighter token revoke --id 1
+
+\n")
+}
+
+///
+///
+///
+pub fn exec(config: &mut Config, args: &ArgMatches) -> FreightResult {
+    let store = TokenStore::new(config.tokens_path.to_owned());
+
+    match args.subcommand() {
+        Some(("create", args)) => {
+            let login = args.get_one::<String>("login").cloned().unwrap();
+            let name = args.get_one::<String>("name").cloned().unwrap();
+            let token = store.create(&login, &name)?;
+            println!("{}", token);
+        }
+        Some(("list", _)) => {
+            for record in store.list()? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    record.id, record.login, record.name, record.created_at
+                );
+            }
+        }
+        Some(("revoke", args)) => {
+            let id = args.get_one::<u32>("id").cloned().unwrap();
+            store.revoke(id)?;
+        }
+        Some((cmd, _)) => {
+            unreachable!("unexpected command {}", cmd)
+        }
+        None => {
+            unreachable!("unexpected command")
+        }
+    };
+
+    Ok(())
+}
@@ -9,6 +9,7 @@ use std::{
     fs,
     io::ErrorKind,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 /// parse config from file
@@ -24,8 +25,18 @@ pub struct Config {
     pub rustup_path: PathBuf,
     #[serde(default = "default_value_for_path")]
     pub dist_path: PathBuf,
-    
+    #[serde(default = "default_value_for_path")]
+    pub owners_path: PathBuf,
+    #[serde(default = "default_value_for_path")]
+    pub tokens_path: PathBuf,
+    #[serde(default = "default_value_for_path")]
+    pub search_path: PathBuf,
+
+    #[serde(default)]
+    pub server: ServerConfig,
     pub crates: CratesConfig,
+    #[serde(default)]
+    pub registries: Vec<RegistryConfig>,
     pub rustup: RustUpConfig,
     pub log: LogConfig,
     pub proxy: ProxyConfig,
@@ -40,6 +51,37 @@ pub struct LogConfig {
     pub limit: u64,
 }
 
+/// config for the registry service
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    pub public_url: String,
+    #[serde(default)]
+    pub auth_required: bool,
+    #[serde(default)]
+    pub login_url: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            public_url: "http://localhost:8000".to_owned(),
+            auth_required: false,
+            login_url: String::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    // the page where users get their tokens, it's shown by cargo when a token is required
+    pub fn login_url(&self) -> Option<String> {
+        if self.login_url.is_empty() {
+            None
+        } else {
+            Some(self.login_url.clone())
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct CratesConfig {
     #[serde(deserialize_with = "path_option_from_str")]
@@ -51,6 +93,106 @@ pub struct CratesConfig {
     pub download_threads: usize,
     pub serve_domains: Option<Vec<String>>,
     pub serve_index: Option<String>,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub owners_path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub tokens_path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub search_path: Option<PathBuf>,
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: usize,
+    #[serde(default)]
+    pub pull_through: bool,
+    #[serde(default = "default_sparse_index_domain")]
+    pub sparse_index_domain: String,
+    #[serde(default = "default_index_ttl")]
+    pub index_ttl: u64,
+    #[serde(default)]
+    pub serve_from_git: bool,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub policy_path: Option<PathBuf>,
+    #[serde(default)]
+    pub git_backend: GitBackend,
+    #[serde(default)]
+    pub lockfiles: Vec<PathBuf>,
+    #[serde(default)]
+    pub roots: Vec<RootCrate>,
+    #[serde(default)]
+    pub include_dev_dependencies: bool,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub skip_yanked: bool,
+    #[serde(default)]
+    pub keep_latest: usize,
+    #[serde(default)]
+    pub published_after: Option<String>,
+}
+
+/// a crate whose dependency closure is mirrored, like `tokio@1` in command line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RootCrate {
+    pub name: String,
+    #[serde(default = "default_req")]
+    pub req: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default = "default_true")]
+    pub default_features: bool,
+}
+
+impl FromStr for RootCrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, req) = s.split_once('@').unwrap_or((s, "*"));
+        if name.is_empty() {
+            return Err(format!("invalid root crate: {}", s));
+        }
+        semver::VersionReq::parse(req)
+            .map_err(|err| format!("invalid version req {}: {}", req, err))?;
+        Ok(RootCrate {
+            name: name.to_owned(),
+            req: req.to_owned(),
+            features: Vec::new(),
+            default_features: true,
+        })
+    }
+}
+
+/// how the git smart http protocol of the served index is handled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GitBackend {
+    // use `git` if it's installed, otherwise fallback to builtin
+    #[default]
+    Auto,
+    // spawn `git http-backend`, supports protocol v2 and partial clone
+    Git,
+    // upload-pack in process with libgit2, supports protocol v0 and shallow clone
+    Builtin,
+}
+
+/// a named registry served under `/{name}`, besides the crates.io mirror
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RegistryConfig {
+    pub name: String,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub index_path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "path_option_from_str")]
+    pub crates_path: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: RegistryMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryMode {
+    // read only, missing crates are fetched from upstream like the crates.io mirror
+    #[default]
+    Mirror,
+    // crates are published to it and only served from local
+    Private,
 }
 
 /// config for rustup mirror sync
@@ -93,6 +235,26 @@ fn default_value_for_path() -> PathBuf {
     PathBuf::new()
 }
 
+fn default_max_upload_size() -> usize {
+    10
+}
+
+fn default_sparse_index_domain() -> String {
+    "https://index.crates.io".to_owned()
+}
+
+fn default_index_ttl() -> u64 {
+    600
+}
+
+fn default_req() -> String {
+    "*".to_owned()
+}
+
+fn default_true() -> bool {
+    true
+}
+
 ///
 impl Config {
     pub fn new() -> Config {
@@ -102,8 +264,13 @@ impl Config {
             log_path: PathBuf::new(),
             rustup_path: PathBuf::new(),
             dist_path: PathBuf::new(),
+            owners_path: PathBuf::new(),
+            tokens_path: PathBuf::new(),
+            search_path: PathBuf::new(),
+            server: ServerConfig::default(),
             rustup: RustUpConfig::default(),
             crates: CratesConfig::default(),
+            registries: Vec::new(),
             log: LogConfig::default(),
             proxy: ProxyConfig::default(),
         }
@@ -118,6 +285,45 @@ impl Config {
         config.log_path = format_path(&config.log.log_path, "log");
         config.rustup_path = format_path(&config.rustup.rustup_path, "rustup");
         config.dist_path = format_path(&config.rustup.dist_path, "dist");
+        config.owners_path = format_path(&config.crates.owners_path, "owners");
+        config.tokens_path = format_path(&config.crates.tokens_path, "tokens");
+        config.search_path = format_path(&config.crates.search_path, "search");
+
+        let registries_path = registries_path(&config.index_path);
+        for registry in config.registries.iter_mut() {
+            let registry_path = registries_path.join(&registry.name);
+            registry
+                .index_path
+                .get_or_insert_with(|| registry_path.join("index"));
+            registry
+                .crates_path
+                .get_or_insert_with(|| registry_path.join("crates"));
+        }
+        config
+    }
+
+    /// the config used to serve a named registry, its urls are under `/{name}` and its
+    /// owners, search index and logs are kept apart from other registries
+    pub fn for_registry(&self, registry: &RegistryConfig) -> Config {
+        let mut config = self.clone();
+        config.index_path = registry.index_path.clone().unwrap_or_default();
+        config.crates_path = registry.crates_path.clone().unwrap_or_default();
+        config.log_path = registries_path(&self.index_path).join(&registry.name).join("log");
+        config.owners_path = self.owners_path.join(&registry.name);
+        config.search_path = self.search_path.join(&registry.name);
+        config.server.public_url = format!(
+            "{}/{}",
+            self.server.public_url.trim_end_matches('/'),
+            registry.name
+        );
+        config.crates.serve_index = None;
+        config.registries = Vec::new();
+        if registry.mode == RegistryMode::Private {
+            // published crates are committed to the index, there is no download to wait for
+            config.crates.serve_from_git = false;
+            config.crates.pull_through = false;
+            config.crates.serve_domains = Some(vec!["localhost".to_owned()]);
+        }
         config
     }
 
@@ -148,6 +354,11 @@ impl Config {
     }
 }
 
+// the work dirs of named registries are next to the crates.io index
+fn registries_path(index_path: &Path) -> PathBuf {
+    index_path.parent().map(|p| p.join("registries")).unwrap_or_default()
+}
+
 pub fn format_path(config_path: &Option<PathBuf>, name: &str) -> PathBuf {
     let default_dir = dirs::home_dir().unwrap().join("freighter");
     let path = match config_path {
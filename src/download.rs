@@ -1,14 +1,28 @@
+//! downloads of crates, toolchains and rustup share one async http client, its connections
+//! are kept alive and reused, and HTTP/2 is used if the server supports it. The requests run
+//! on a tokio runtime owned by [`Downloader`], and the number of requests in flight is bounded
+//! by a semaphore.
 //!
-//!
-//!
-//!
-//!
+//! The handlers are sync, they call [`Downloader::block_on`] or queue downloads with
+//! [`Downloader::spawn`], which waits when too many downloads are queued. These methods
+//! block the calling thread, so they panic if called from async code, async callers
+//! should await the download futures directly.
 //!
 
 use std::{
-    fs::{self, File},
-    io::{self, BufWriter},
+    fs::File,
+    future::Future,
+    io,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    runtime::{Handle, Runtime},
+    sync::Semaphore,
 };
 
 use crate::config::ProxyConfig;
@@ -18,85 +32,166 @@ use sha2::{Digest, Sha256};
 use url::form_urlencoded::byte_serialize;
 use url::Url;
 
-pub trait Download {
+#[async_trait]
+pub trait Download: Send + Sync {
     /// download file to a folder with given url and path
     /// return false if connect success but download failed
-    fn download_to_folder(&self, msg: &str) -> Result<bool, FreighterError>;
-}
-
-/// use reqwest to handle https download requests
-pub struct BlockingReqwest {
-    pub opts: DownloadOptions,
+    async fn download_to_folder(
+        &self,
+        opts: &DownloadOptions,
+        msg: &str,
+    ) -> Result<bool, FreighterError>;
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct DownloadOptions {
-    pub proxy: ProxyConfig,
     pub url: Url,
     pub path: PathBuf,
 }
 
-impl Download for BlockingReqwest {
-    fn download_to_folder(&self, prefix_msg: &str) -> Result<bool, FreighterError> {
-        let DownloadOptions { proxy, url, path } = &self.opts;
+/// the shared http client and runtime of downloads, it's cheap to clone
+#[derive(Clone, Debug)]
+pub struct Downloader {
+    client: reqwest::Client,
+    runtime: Arc<Runtime>,
+    // bounds the requests in flight
+    requests: Arc<Semaphore>,
+    // bounds the tasks queued by `spawn`, so the callers don't run too far ahead
+    tasks: Arc<Semaphore>,
+    concurrency: usize,
+}
+
+impl Downloader {
+    pub fn new(proxy: &ProxyConfig, concurrency: usize) -> Result<Downloader, FreighterError> {
+        let concurrency = concurrency.max(1);
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(concurrency)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .connect_timeout(Duration::from_secs(30))
+            .http2_adaptive_window(true);
+        if proxy.enable {
+            builder = builder.proxy(reqwest::Proxy::all(&proxy.download_proxy)?);
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("freighter-download")
+            .build()?;
+        Ok(Downloader {
+            client: builder.build()?,
+            runtime: Arc::new(runtime),
+            requests: Arc::new(Semaphore::new(concurrency)),
+            tasks: Arc::new(Semaphore::new(concurrency * 2)),
+            concurrency,
+        })
+    }
+
+    // blocking a runtime thread would stall other tasks, tokio panics on it without telling
+    // which call is wrong
+    fn assert_sync(method: &str) {
+        assert!(
+            Handle::try_current().is_err(),
+            "Downloader::{} blocks and must not be called from async code",
+            method
+        );
+    }
+
+    /// run a future on the download runtime and wait for it, it must not be called from async code
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        Self::assert_sync("block_on");
+        self.runtime.block_on(future)
+    }
+
+    /// queue a download task, it waits when too many tasks are queued, so it must not be
+    /// called from async code
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self::assert_sync("spawn");
+        // the semaphores are never closed
+        let permit = self
+            .runtime
+            .block_on(self.tasks.clone().acquire_owned())
+            .unwrap();
+        self.runtime.spawn(async move {
+            task.await;
+            drop(permit);
+        });
+    }
+
+    /// wait for all tasks queued by `spawn`, it must not be called from async code
+    pub fn wait(&self) {
+        Self::assert_sync("wait");
+        self.runtime.block_on(async {
+            let all = (self.concurrency * 2) as u32;
+            let _permits = self.tasks.acquire_many(all).await.unwrap();
+        });
+    }
+}
+
+#[async_trait]
+impl Download for Downloader {
+    async fn download_to_folder(
+        &self,
+        opts: &DownloadOptions,
+        prefix_msg: &str,
+    ) -> Result<bool, FreighterError> {
+        let DownloadOptions { url, path } = opts;
+        let _permit = self.requests.acquire().await.unwrap();
 
-        let client_builder = reqwest::blocking::Client::builder();
-        let reqwest_client = if proxy.enable {
-            let proxy = reqwest::Proxy::all(proxy.download_proxy.clone()).unwrap();
-            client_builder.proxy(proxy).build().unwrap()
-        } else {
-            client_builder.build().unwrap()
-        };
         let mut url = url.clone();
         encode_huaweicloud_url(&mut url);
-        let mut resp = reqwest_client.get(url.clone()).send()?;
-        if resp.status().is_success() {
-            // generate parent folder if not exist
-            if let Some(parent) = path.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent).unwrap();
-                }
-            }
-            let mut out = BufWriter::new(File::create(path).unwrap());
-            io::copy(&mut resp, &mut out).unwrap();
-            tracing::info!("{} {:?}", prefix_msg, out.get_ref());
-        } else {
+        let mut resp = self.client.get(url.clone()).send().await?;
+        if !resp.status().is_success() {
             tracing::error!(
                 "download failed, Please check your url: {}",
                 url.to_string()
             );
             return Ok(false);
         }
+        // generate parent folder if not exist
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // write to a temporary file first, so an interrupted download doesn't leave a broken file
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let mut out = BufWriter::new(tokio::fs::File::create(&part).await?);
+        while let Some(chunk) = resp.chunk().await? {
+            out.write_all(&chunk).await?;
+        }
+        out.flush().await?;
+        tokio::fs::rename(&part, path).await?;
+        tracing::info!("{} {}", prefix_msg, path.display());
         Ok(true)
     }
 }
 
 // download remote sha file and then download file for hash check
-pub fn download_file_with_sha(
+pub async fn download_file_with_sha(
+    downloader: &impl Download,
     url: &str,
     file_folder: &Path,
     file_name: &str,
-    proxy: &ProxyConfig,
 ) -> Result<bool, FreighterError> {
     let sha_url = format!("{}{}", url, ".sha256");
     let sha_name = format!("{}{}", file_name, ".sha256");
     let sha_path = file_folder.join(sha_name);
     //always update sha256 file
     let down_sha = &DownloadOptions {
-        proxy: proxy.clone(),
         url: Url::parse(&sha_url).unwrap(),
         path: sha_path,
     };
-    let res = download_and_check_hash(down_sha, None, true).unwrap();
+    let res = download_and_check_hash(downloader, down_sha, None, true).await?;
     if res {
-        let content = fs::read_to_string(&down_sha.path).unwrap();
+        let content = tokio::fs::read_to_string(&down_sha.path).await?;
         let sha256 = &content[..64];
         let down_file = &DownloadOptions {
-            proxy: proxy.clone(),
             url: Url::parse(url).unwrap(),
             path: file_folder.join(file_name),
         };
-        download_and_check_hash(down_file, Some(sha256), false)
+        download_and_check_hash(downloader, down_file, Some(sha256), false).await
     } else {
         Ok(false)
     }
@@ -106,31 +201,30 @@ pub fn download_file_with_sha(
 /// return true if download and success, return false if file already exists
 /// -- check_sum: weather need to check hash before download
 /// -- is_override: override file if check_sum is none
-pub fn download_and_check_hash(
+pub async fn download_and_check_hash(
+    downloader: &impl Download,
     opts: &DownloadOptions,
     check_sum: Option<&str>,
     is_override: bool,
 ) -> Result<bool, FreighterError> {
-    let br = BlockingReqwest {
-        opts: opts.to_owned(),
-    };
     let path = &opts.path;
-    if path.is_file() && path.exists() {
-        let mut hasher = Sha256::new();
-        let mut file = File::open(path)?;
-        io::copy(&mut file, &mut hasher)?;
-        let result = hasher.finalize();
-        let hex = format!("{:x}", result);
-
+    if path.is_file() {
         //if need to calculate hash
-        if check_sum.is_some() {
-            return if hex == check_sum.unwrap() {
-                tracing::info!("###[ALREADY] \t{:?}", file);
+        if let Some(check_sum) = check_sum {
+            // hashing a large toolchain file shouldn't block the runtime
+            let file = path.clone();
+            let hex = tokio::task::spawn_blocking(move || sha256_file(&file))
+                .await
+                .map_err(anyhow::Error::from)??;
+            return if hex == check_sum {
+                tracing::info!("###[ALREADY] \t{}", path.display());
                 Ok(false)
             } else {
-                tracing::warn!("!!![REMOVE] \t\t {:?} !", file);
-                fs::remove_file(path)?;
-                br.download_to_folder("!!![REMOVED DOWNLOAD] \t\t ")
+                tracing::warn!("!!![REMOVE] \t\t {} !", path.display());
+                tokio::fs::remove_file(path).await?;
+                downloader
+                    .download_to_folder(opts, "!!![REMOVED DOWNLOAD] \t\t ")
+                    .await
             };
         } else if !is_override {
             tracing::info!(
@@ -140,7 +234,14 @@ pub fn download_and_check_hash(
             return Ok(false);
         }
     }
-    br.download_to_folder("&&&[NEW] \t\t ")
+    downloader.download_to_folder(opts, "&&&[NEW] \t\t ").await
+}
+
+fn sha256_file(path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    let mut file = File::open(path)?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
 pub fn encode_huaweicloud_url(url: &mut Url) {
@@ -158,10 +259,66 @@ pub fn encode_huaweicloud_url(url: &mut Url) {
 
 #[cfg(test)]
 mod tests {
+    use std::{env, fs};
 
     use reqwest::Url;
+    use sha2::{Digest, Sha256};
+    use warp::Filter;
+
+    use crate::config::ProxyConfig;
+    use crate::download::{self, download_and_check_hash, DownloadOptions, Downloader};
 
-    use crate::download;
+    #[test]
+    fn test_download_and_check_hash() {
+        let downloader = Downloader::new(&ProxyConfig::default(), 16).unwrap();
+        let addr = downloader.block_on(async {
+            let route = warp::path!("crates" / "a.crate").map(|| "crate content");
+            let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            addr
+        });
+        let dir = env::temp_dir().join("freighter-test-download");
+        let _ = fs::remove_dir_all(&dir);
+        let download = |name: &str, check_sum: Option<&str>| {
+            let opts = DownloadOptions {
+                url: Url::parse(&format!("http://{}/crates/{}", addr, name)).unwrap(),
+                path: dir.join("a").join(name),
+            };
+            downloader
+                .block_on(download_and_check_hash(
+                    &downloader,
+                    &opts,
+                    check_sum,
+                    false,
+                ))
+                .unwrap()
+        };
+
+        let cksum = format!("{:x}", Sha256::digest(b"crate content"));
+        assert!(download("a.crate", Some(&cksum)));
+        let path = dir.join("a/a.crate");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "crate content");
+        // the file is kept if it passes the check
+        assert!(!download("a.crate", Some(&cksum)));
+        // and downloaded again if it's broken
+        fs::write(&path, "broken").unwrap();
+        assert!(download("a.crate", Some(&cksum)));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "crate content");
+
+        assert!(!download("b.crate", None));
+        assert!(!dir.join("a/b.crate").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Downloader::wait blocks and must not be called from async code")]
+    fn test_wait_in_async_code() {
+        let downloader = Downloader::new(&ProxyConfig::default(), 1).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async { downloader.wait() });
+    }
 
     #[test]
     fn test_huaweicloud_url_serial() {
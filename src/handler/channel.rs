@@ -13,10 +13,7 @@ use std::{
 };
 
 use chrono::{Duration, NaiveDate, Utc};
-use rayon::{
-    prelude::{IntoParallelRefIterator, ParallelIterator},
-    ThreadPool, ThreadPoolBuilder,
-};
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde::Deserialize;
 use url::Url;
 use walkdir::WalkDir;
@@ -24,10 +21,11 @@ use walkdir::WalkDir;
 use crate::{
     cloud::{s3::S3cmd, CloudStorage},
     config::{ProxyConfig, RustUpConfig},
-    download::{download_and_check_hash, download_file_with_sha, DownloadOptions},
+    download::{download_and_check_hash, download_file_with_sha, DownloadOptions, Downloader},
     errors::{FreightResult, FreighterError},
 };
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Channel {
     #[serde(alias = "manifest-version")]
@@ -36,12 +34,14 @@ pub struct Channel {
     pub pkg: HashMap<String, Pkg>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Pkg {
     pub version: String,
     pub target: HashMap<String, Target>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Target {
     pub available: bool,
@@ -79,6 +79,9 @@ pub struct ChannelOptions {
     pub init: bool,
 
     pub thread_pool: Arc<ThreadPool>,
+
+    // set by `exec`, like the downloader of `CratesOptions`
+    pub downloader: Option<Downloader>,
 }
 
 impl Default for ChannelOptions {
@@ -96,10 +99,20 @@ impl Default for ChannelOptions {
             delete_after_upload: false,
             sync_history: false,
             init: false,
+            downloader: None,
         }
     }
 }
 
+impl ChannelOptions {
+    /// the downloader shared by the toolchain files
+    pub fn downloader(&self) -> &Downloader {
+        self.downloader
+            .as_ref()
+            .expect("the downloader is set by exec")
+    }
+}
+
 /// entrance function
 pub fn sync_rust_toolchain(opts: &ChannelOptions) -> FreightResult {
     let config = &opts.config;
@@ -171,7 +184,14 @@ pub fn sync_channel(opts: &ChannelOptions, channel: &str) -> FreightResult {
         channel_url = format!("{}/dist/{}", opts.config.domain, channel_name);
         channel_folder = opts.dist_path.to_owned();
     }
-    match download_file_with_sha(&channel_url, &channel_folder, &channel_name, &opts.proxy) {
+    let downloader = opts.downloader();
+    let res = downloader.block_on(download_file_with_sha(
+        downloader,
+        &channel_url,
+        &channel_folder,
+        &channel_name,
+    ));
+    match res {
         Ok(res) => {
             let channel_toml = &channel_folder.join(channel_name);
             if !res && !channel_toml.exists() {
@@ -181,49 +201,41 @@ pub fn sync_channel(opts: &ChannelOptions, channel: &str) -> FreightResult {
             // parse_channel_file and download;
             let download_list = parse_channel_file(channel_toml).unwrap();
             let s3cmd = Arc::new(S3cmd::default());
-            opts.thread_pool.install(|| {
-                download_list.par_iter().for_each(|(url, hash)| {
-                    // example: https://static.rust-lang.org/dist/2022-11-03/rust-1.65.0-i686-pc-windows-gnu.msi
-                    // these code was used to remove url prefix "https://static.rust-lang.org/dist"
-                    // and get "2022-11-03/rust-1.65.0-i686-pc-windows-gnu.msi"
-                    let path: PathBuf = std::iter::once(opts.dist_path.to_owned())
-                        .chain(
-                            url.split('/').map(PathBuf::from).collect::<Vec<PathBuf>>()[4..]
-                                .to_owned(),
-                        )
-                        .collect();
-                    let (upload, dist_path, bucket, delete_after_upload) = (
-                        opts.upload,
-                        opts.dist_path.to_owned(),
-                        opts.bucket.to_owned(),
-                        opts.delete_after_upload,
-                    );
+            for (url, hash) in download_list {
+                // example: https://static.rust-lang.org/dist/2022-11-03/rust-1.65.0-i686-pc-windows-gnu.msi
+                // these code was used to remove url prefix "https://static.rust-lang.org/dist"
+                // and get "2022-11-03/rust-1.65.0-i686-pc-windows-gnu.msi"
+                let path: PathBuf = std::iter::once(opts.dist_path.to_owned())
+                    .chain(
+                        url.split('/').map(PathBuf::from).collect::<Vec<PathBuf>>()[4..].to_owned(),
+                    )
+                    .collect();
 
-                    let mut url = Url::parse(url).unwrap();
-                    url.set_host(Url::parse(&opts.config.domain).unwrap().host_str())
-                        .unwrap();
+                let mut url = Url::parse(&url).unwrap();
+                url.set_host(Url::parse(&opts.config.domain).unwrap().host_str())
+                    .unwrap();
 
-                    let down_opts = &DownloadOptions {
-                        proxy: opts.proxy.clone(),
-                        url,
-                        path,
-                    };
-                    let path = &down_opts.path;
-                    let downloaded = download_and_check_hash(down_opts, Some(hash), false).unwrap();
-                    if downloaded && upload {
-                        let s3_path = format!(
-                            "dist{}",
-                            path.to_str()
-                                .unwrap()
-                                .replace(dist_path.to_str().unwrap(), "")
-                        );
-                        let uploaded = s3cmd.upload_file(path, &s3_path, &bucket.unwrap());
-                        if uploaded.is_ok() && delete_after_upload {
-                            fs::remove_file(path).unwrap();
+                let (opts, s3cmd) = (opts.clone(), s3cmd.clone());
+                downloader.spawn(async move {
+                    let down_opts = &DownloadOptions { url, path };
+                    let downloaded =
+                        download_and_check_hash(opts.downloader(), down_opts, Some(&hash), false)
+                            .await;
+                    match downloaded {
+                        Ok(true) if opts.upload => {
+                            // s3cmd is a blocking process
+                            tokio::task::block_in_place(|| {
+                                upload_dist_file(&opts, &s3cmd, &down_opts.path)
+                            });
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            tracing::error!("failed to download {}: {:?}", down_opts.url, err)
                         }
-                    };
+                    }
                 });
-            });
+            }
+            downloader.wait();
 
             replace_toml_and_sha(opts, s3cmd, channel_toml);
         }
@@ -234,6 +246,19 @@ pub fn sync_channel(opts: &ChannelOptions, channel: &str) -> FreightResult {
     Ok(())
 }
 
+fn upload_dist_file(opts: &ChannelOptions, s3cmd: &S3cmd, path: &Path) {
+    let s3_path = format!(
+        "dist{}",
+        path.to_str()
+            .unwrap()
+            .replace(opts.dist_path.to_str().unwrap(), "")
+    );
+    let uploaded = s3cmd.upload_file(path, &s3_path, opts.bucket.as_ref().unwrap());
+    if uploaded.is_ok() && opts.delete_after_upload {
+        fs::remove_file(path).unwrap();
+    }
+}
+
 // upload toml file and sha256 after all files handle success
 pub fn replace_toml_and_sha(opts: &ChannelOptions, s3cmd: Arc<S3cmd>, channel_toml: &Path) {
     let shafile = channel_toml.with_extension("toml.sha256");
@@ -258,18 +283,16 @@ pub fn parse_channel_file(path: &Path) -> Result<Vec<(String, String)>, Freighte
     let channel: Channel = toml::from_str(&content).unwrap();
     let res: Vec<(String, String)> = channel
         .pkg
-        .into_iter()
-        .flat_map(|(_, pkg)| {
+        .into_values()
+        .flat_map(|pkg| {
             pkg.target
-                .into_iter()
-                .flat_map(|(_, target)| -> Vec<(String, String)> {
+                .into_values()
+                .flat_map(|target| -> Vec<(String, String)> {
                     let mut result: Vec<(String, String)> = Vec::new();
-                    if target.xz_url.is_some() && target.xz_hash.is_some() {
-                        result.push((target.xz_url.unwrap(), target.xz_hash.unwrap()));
+                    if let (Some(xz_url), Some(xz_hash)) = (target.xz_url, target.xz_hash) {
+                        result.push((xz_url, xz_hash));
                     }
-                    if target.url.is_some() && target.hash.is_some() {
-                        let url = target.url.unwrap();
-                        let hash = target.hash.unwrap();
+                    if let (Some(url), Some(hash)) = (target.url, target.hash) {
                         if !url.is_empty() && !hash.is_empty() {
                             result.push((url, hash));
                         }
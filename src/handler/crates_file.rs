@@ -7,7 +7,7 @@
 
 use std::io::Write;
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs::{self, File, OpenOptions};
 use std::io::{BufRead, BufReader, ErrorKind};
 use std::path::{Path, PathBuf};
@@ -15,19 +15,24 @@ use std::str;
 use std::sync::{Arc, Mutex};
 
 use chrono::Utc;
-use rayon::{Scope, ThreadPool, ThreadPoolBuilder};
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use walkdir::{DirEntry, WalkDir};
 
 use crate::cloud::s3::S3cmd;
 use crate::cloud::{self, CloudStorage};
-use crate::config::{CratesConfig, ProxyConfig};
-use crate::download::{download_and_check_hash, DownloadOptions};
+use crate::config::{CratesConfig, ProxyConfig, ServerConfig};
+use crate::download::{download_and_check_hash, DownloadOptions, Downloader};
 use crate::errors::FreightResult;
 use crate::handler::index;
+use crate::server::owners::OwnerStore;
 
 use super::index::CrateIndex;
+use super::lockfile;
+use super::policy::Policy;
+use super::resolver::Resolver;
+use super::retention::Retention;
 use super::{utils, DownloadMode};
 
 /// CratesOptions preserve the sync subcommand config
@@ -35,6 +40,12 @@ use super::{utils, DownloadMode};
 pub struct CratesOptions {
     pub config: CratesConfig,
 
+    pub server: ServerConfig,
+
+    pub policy: Arc<Policy>,
+
+    pub retention: Arc<Retention>,
+
     pub proxy: ProxyConfig,
 
     pub index: CrateIndex,
@@ -54,11 +65,19 @@ pub struct CratesOptions {
 
     pub log_path: PathBuf,
 
+    pub search_path: PathBuf,
+
+    // the owners of locally published crates
+    pub owners_path: PathBuf,
+
     pub bucket_name: String,
 
     pub delete_after_upload: bool,
 
     pub thread_pool: Arc<ThreadPool>,
+
+    // created by `exec` with the configured concurrency, so the defaults don't build one
+    pub downloader: Option<Downloader>,
 }
 
 impl Default for CratesOptions {
@@ -67,6 +86,9 @@ impl Default for CratesOptions {
         CratesOptions {
             thread_pool,
             config: CratesConfig::default(),
+            server: ServerConfig::default(),
+            policy: Arc::new(Policy::default()),
+            retention: Arc::new(Retention::default()),
             proxy: ProxyConfig::default(),
             index: CrateIndex::default(),
             no_progressbar: false,
@@ -75,13 +97,23 @@ impl Default for CratesOptions {
             crates_path: PathBuf::default(),
             crates_name: None,
             log_path: PathBuf::default(),
+            search_path: PathBuf::default(),
+            owners_path: PathBuf::default(),
             bucket_name: String::default(),
             delete_after_upload: false,
+            downloader: None,
         }
     }
 }
 
 impl CratesOptions {
+    /// the shared downloader, it's created by `exec` with the configured concurrency
+    pub fn downloader(&self) -> &Downloader {
+        self.downloader
+            .as_ref()
+            .expect("the downloader is created before any download")
+    }
+
     // the path rules of craes index file
     pub fn get_index_path(&self, name: &str) -> PathBuf {
         let suffix = utils::index_suffix(name);
@@ -105,6 +137,11 @@ pub struct IndexFile {
     pub links: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub v: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
+    // the publish time in RFC 3339, it's missing in the lines of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubtime: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -127,6 +164,8 @@ pub struct Dependency {
     pub target: Option<String>,
     pub kind: Option<DependencyKind>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub package: Option<String>,
 }
 
@@ -143,14 +182,37 @@ pub enum DependencyKind {
 
 /// full download and Incremental download from registry
 pub fn download(opts: &CratesOptions) -> FreightResult {
+    let logged_errors = error_log_len(&opts.log_path);
     match opts.download_mode {
-        DownloadMode::Init => full_downloads(opts).unwrap(),
         DownloadMode::Fix => fix_download(opts).unwrap(),
+        // only the locked or resolved crates are mirrored, the missing ones are downloaded on every run
+        _ if !opts.config.lockfiles.is_empty() || !opts.config.roots.is_empty() => {
+            selected_downloads(opts)?
+        }
+        DownloadMode::Init => full_downloads(opts).unwrap(),
         DownloadMode::Increment => incremental_download(opts).unwrap(),
     }
+    // crates of the pulled index are all downloaded, the new index can be served now
+    if opts.config.serve_from_git
+        && !matches!(opts.download_mode, DownloadMode::Fix)
+        && opts.crates_name.is_none()
+    {
+        if error_log_len(&opts.log_path) > logged_errors {
+            tracing::warn!("some crates failed to download, keep serving the previous index");
+        } else {
+            index::advance_served_ref(&opts.index.path)?;
+        }
+    }
     Ok(())
 }
 
+// the failed downloads are appended to the error log, so it grows when a download fails
+fn error_log_len(log_path: &Path) -> u64 {
+    fs::metadata(log_path.join("error-crates.log"))
+        .map(|meta| meta.len())
+        .unwrap_or_default()
+}
+
 /// <https://github.com/rust-lang/crates.io-index/blob/master/.github/workflows/update-dl-url.yml>
 ///
 /// ```YAML
@@ -162,23 +224,21 @@ pub fn download(opts: &CratesOptions) -> FreightResult {
 /// ```
 pub fn full_downloads(opts: &CratesOptions) -> FreightResult {
     let err_record = open_file_with_mutex(&opts.log_path);
-    opts.thread_pool.scope(|s| {
-        WalkDir::new(&opts.index.path)
-            .into_iter()
-            .filter_entry(is_not_hidden)
-            .filter_map(|v| v.ok())
-            .for_each(|x| {
-                if x.file_type().is_file() && x.path().extension().unwrap_or_default() != "json" {
-                    parse_index_and_download(&x.path().to_path_buf(), opts, s, &err_record)
-                        .unwrap();
-                }
-            });
-    });
+    WalkDir::new(&opts.index.path)
+        .into_iter()
+        .filter_entry(is_not_hidden)
+        .filter_map(|v| v.ok())
+        .for_each(|x| {
+            if x.file_type().is_file() && x.path().extension().unwrap_or_default() != "json" {
+                parse_index_and_download(x.path(), opts, &err_record).unwrap();
+            }
+        });
+    opts.downloader().wait();
     Ok(())
 }
 
 pub fn incremental_download(opts: &CratesOptions) -> FreightResult {
-    tracing::info!("{:?}", opts.thread_pool);
+    tracing::info!("{:?}", opts.downloader);
     let it = WalkDir::new(&opts.log_path)
         .into_iter()
         .filter_entry(|e| {
@@ -214,6 +274,122 @@ pub fn incremental_download(opts: &CratesOptions) -> FreightResult {
         tracing::info!("{:?}", line);
         index::git2_diff(opts, vec[0], vec[1], err_record).unwrap();
     }
+    opts.downloader().wait();
+    Ok(())
+}
+
+/// download the crates.io packages locked by the lockfiles and the dependency closure of the
+/// root crates, the versions are looked up in the local index for their checksums, so
+/// `crates pull` should be run before
+pub fn selected_downloads(opts: &CratesOptions) -> FreightResult {
+    let selected = selected_crates(opts)?;
+    tracing::info!(
+        "download {} crates locked by {:?} or required by {:?}",
+        selected.len(),
+        opts.config.lockfiles,
+        opts.config.roots
+    );
+    let err_record = open_file_with_mutex(&opts.log_path);
+    for (name, versions) in &selected {
+        let index_path = opts.get_index_path(&name.to_lowercase());
+        parse_index_and_download_versions(&index_path, opts, &err_record, Some(versions))?;
+    }
+    opts.downloader().wait();
+    Ok(())
+}
+
+/// the versions locked by the lockfiles and resolved from the root crates
+pub fn selected_crates(
+    opts: &CratesOptions,
+) -> Result<BTreeMap<String, BTreeSet<String>>, anyhow::Error> {
+    let mut selected = lockfile::read_locked_crates(&opts.config.lockfiles)?;
+    if !opts.config.roots.is_empty() {
+        let resolved =
+            Resolver::new(opts.index.path.clone(), &opts.config).resolve(&opts.config.roots)?;
+        for (name, versions) in resolved {
+            selected.entry(name).or_default().extend(versions);
+        }
+    }
+    Ok(selected)
+}
+
+/// delete the downloaded versions which are not kept by the retention rules or denied by the
+/// policy, the copies in the bucket are deleted too if it's set. Versions selected by lockfiles
+/// or root crates are always kept.
+///
+/// Only the local files are walked, so the copies of files deleted after upload are left in
+/// the bucket. Crates published to this registry don't come from upstream, so they are never
+/// pruned.
+pub fn prune(opts: &CratesOptions, dry_run: bool) -> FreightResult {
+    if opts.retention.is_empty() && opts.policy.is_empty() {
+        tracing::info!("nothing to prune without retention rules or policy");
+        return Ok(());
+    }
+    let selected = selected_crates(opts)?;
+    let s3 = S3cmd::default();
+    let owners = OwnerStore::new(opts.owners_path.clone());
+    let mut pruned = 0;
+    let crate_dirs = WalkDir::new(&opts.crates_path)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_entry(is_not_hidden)
+        .filter_map(|v| v.ok())
+        .filter(|entry| entry.file_type().is_dir());
+    for entry in crate_dirs {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // the first publisher of a crate becomes its owner, only published crates have owners
+        match owners.list(&name) {
+            Ok(owners) if owners.is_empty() => {}
+            Ok(_) => {
+                tracing::info!("skip pruning {}: it's published locally", name);
+                continue;
+            }
+            Err(err) => {
+                tracing::warn!("skip pruning {}: {}", name, err);
+                continue;
+            }
+        }
+        let files = match read_index_file(&opts.get_index_path(&name.to_lowercase())) {
+            Ok(files) => files,
+            Err(err) => {
+                tracing::warn!("skip pruning {}: {}", name, err);
+                continue;
+            }
+        };
+        // versions removed from the index are unknown to the rules, leave them alone
+        let known: HashSet<&str> = files.iter().map(|file| file.vers.as_str()).collect();
+        let retained = opts.retention.retain(&files);
+        for file in fs::read_dir(entry.path())? {
+            let file = file?;
+            let file_name = file.file_name().to_string_lossy().to_string();
+            let vers = match file_name
+                .strip_prefix(&format!("{}-", name))
+                .and_then(|s| s.strip_suffix(".crate"))
+            {
+                Some(vers) if known.contains(vers) => vers,
+                _ => continue,
+            };
+            let keep = selected.get(&name).is_some_and(|v| v.contains(vers))
+                || (retained.contains(vers) && opts.policy.check(&name, vers).is_ok());
+            if keep {
+                continue;
+            }
+            tracing::info!("prune {}", file.path().display());
+            pruned += 1;
+            if dry_run {
+                continue;
+            }
+            fs::remove_file(file.path())?;
+            if !opts.bucket_name.is_empty() {
+                let s3_path = format!("crates/{}/{}", name, file_name);
+                if let Err(err) = s3.delete_file(&s3_path, &opts.bucket_name) {
+                    tracing::error!("failed to delete {} from bucket: {:?}", s3_path, err);
+                }
+            }
+        }
+    }
+    tracing::info!("{} crate files are pruned", pruned);
     Ok(())
 }
 
@@ -224,33 +400,33 @@ pub fn fix_download(opts: &CratesOptions) -> FreightResult {
     let mut visited: HashSet<String> = HashSet::new();
     let err_record_with_mutex = open_file_with_mutex(&opts.log_path);
 
-    opts.thread_pool.scope(|s| {
-        if opts.crates_name.is_some() {
-            let index_path = opts.get_index_path(&opts.crates_name.clone().unwrap());
-            parse_index_and_download(&index_path, opts, s, &err_record_with_mutex).unwrap();
-        } else {
-            let err_record = OpenOptions::new().read(true).open(file_name).unwrap();
-            let buffered = BufReader::new(err_record);
-            for line in buffered.lines() {
-                let line = line.unwrap();
-                let c: ErrorCrate = serde_json::from_str(&line).unwrap();
-                let ErrorCrate {
-                    name,
-                    vers,
-                    time: _,
-                } = c;
-                if !visited.contains(&name) {
-                    let index_path = opts.get_index_path(&name);
-                    parse_index_and_download(&index_path, opts, s, &err_record_with_mutex).unwrap();
-                    visited.insert(name.to_owned());
-                    tracing::info!("handle success: {}-{}", &name, &vers);
-                } else {
-                    // skipping visited
-                    tracing::info!("skip different version of same crates: {}-{}", &name, &vers);
-                }
+    if opts.crates_name.is_some() {
+        let index_path = opts.get_index_path(&opts.crates_name.clone().unwrap());
+        parse_index_and_download(&index_path, opts, &err_record_with_mutex).unwrap();
+    } else {
+        let err_record = OpenOptions::new().read(true).open(file_name).unwrap();
+        let buffered = BufReader::new(err_record);
+        for line in buffered.lines() {
+            let line = line.unwrap();
+            let c: ErrorCrate = serde_json::from_str(&line).unwrap();
+            let ErrorCrate {
+                name,
+                vers,
+                time: _,
+            } = c;
+            if !visited.contains(&name) {
+                let index_path = opts.get_index_path(&name);
+                parse_index_and_download(&index_path, opts, &err_record_with_mutex).unwrap();
+                visited.insert(name.to_owned());
+                tracing::info!("handle success: {}-{}", &name, &vers);
+            } else {
+                // skipping visited
+                tracing::info!("skip different version of same crates: {}-{}", &name, &vers);
             }
         }
-    });
+    }
+    // the failed downloads are appended to the error log, wait for them before it's removed
+    opts.downloader().wait();
 
     if opts.crates_name.is_none() {
         fs::remove_file(file_name).unwrap();
@@ -282,7 +458,7 @@ pub fn upload_to_s3(opts: &CratesOptions) -> FreightResult {
 /// open error record file with Mutex
 pub fn open_file_with_mutex(log_path: &Path) -> Arc<Mutex<File>> {
     let file_name = log_path.join("error-crates.log");
-    let err_record = match OpenOptions::new().write(true).append(true).open(&file_name) {
+    let err_record = match OpenOptions::new().append(true).open(&file_name) {
         Ok(f) => Arc::new(Mutex::new(f)),
         Err(err) => match err.kind() {
             ErrorKind::NotFound => Arc::new(Mutex::new(File::create(&file_name).unwrap())),
@@ -302,18 +478,40 @@ pub fn is_not_hidden(entry: &DirEntry) -> bool {
 }
 
 pub fn parse_index_and_download(
-    index_path: &PathBuf,
+    index_path: &Path,
     opts: &CratesOptions,
-    scope: &Scope,
     err_record: &Arc<Mutex<File>>,
 ) -> FreightResult {
-    match File::open(index_path) {
-        Ok(f) => {
-            let buffered = BufReader::new(f);
+    parse_index_and_download_versions(index_path, opts, err_record, None)
+}
 
-            for line in buffered.lines() {
-                let line = line.unwrap();
-                let c: IndexFile = serde_json::from_str(&line).unwrap();
+/// download the given versions in the index file, or all versions if it's None, the downloads
+/// are queued on the downloader, call `Downloader::wait` for them to finish
+pub fn parse_index_and_download_versions(
+    index_path: &Path,
+    opts: &CratesOptions,
+    err_record: &Arc<Mutex<File>>,
+    versions: Option<&BTreeSet<String>>,
+) -> FreightResult {
+    match read_index_file(index_path) {
+        Ok(files) => {
+            // the retention rules don't apply to the explicitly selected versions
+            let retained = versions.is_none().then(|| opts.retention.retain(&files));
+            let mut found = 0;
+
+            for c in files {
+                if versions.is_some_and(|versions| !versions.contains(&c.vers)) {
+                    continue;
+                }
+                found += 1;
+                if retained.as_ref().is_some_and(|r| !r.contains(&c.vers)) {
+                    tracing::debug!("skip download: {}-{} is not retained", c.name, c.vers);
+                    continue;
+                }
+                if let Err(reason) = opts.policy.check(&c.name, &c.vers) {
+                    tracing::info!("skip download: {}", reason);
+                    continue;
+                }
                 let err_record = Arc::clone(err_record);
                 let opts = opts.clone();
 
@@ -328,10 +526,18 @@ pub fn parse_index_and_download(
                     .join(&c.name)
                     .join(format!("{}-{}.crate", &c.name, &c.vers));
 
-                scope.spawn(move |_| {
-                    download_crates_with_log(file, &opts, url, c, err_record).unwrap();
+                opts.downloader().clone().spawn(async move {
+                    // the failure is recorded in the error log for `download --fix`
+                    let _ = download_crates_with_log(file, &opts, url, c, err_record).await;
                 });
             }
+            if let Some(versions) = versions.filter(|versions| versions.len() > found) {
+                tracing::warn!(
+                    "some versions of {:?} are not found in {}, try crates pull first",
+                    versions,
+                    index_path.display()
+                );
+            }
         }
         Err(err) => match err.kind() {
             ErrorKind::NotFound => {
@@ -346,35 +552,32 @@ pub fn parse_index_and_download(
     Ok(())
 }
 
-pub fn download_crates_with_log(
+/// parse all lines of an index file
+pub fn read_index_file(index_path: &Path) -> Result<Vec<IndexFile>, std::io::Error> {
+    let buffered = BufReader::new(File::open(index_path)?);
+    let mut files = Vec::new();
+    for line in buffered.lines() {
+        files.push(serde_json::from_str(&line?)?);
+    }
+    Ok(files)
+}
+
+pub async fn download_crates_with_log(
     path: PathBuf,
     opts: &CratesOptions,
     url: Url,
     index_file: IndexFile,
     err_record: Arc<Mutex<File>>,
 ) -> FreightResult {
-    let down_opts = &DownloadOptions {
-        proxy: opts.proxy.clone(),
-        url,
-        path,
-    };
+    let down_opts = &DownloadOptions { url, path };
+    let check_sum = index_file.cksum.as_deref();
 
-    match download_and_check_hash(down_opts, Some(&index_file.cksum.unwrap()), false) {
+    match download_and_check_hash(opts.downloader(), down_opts, check_sum, false).await {
         Ok(download_succ) => {
             let path = &down_opts.path;
             if download_succ && opts.upload {
-                let s3 = S3cmd::default();
-                let s3_path = format!(
-                    "crates{}",
-                    path.to_str()
-                        .unwrap()
-                        .replace(opts.crates_path.to_str().unwrap(), "")
-                );
-                tracing::info!("s3_path: {}, {}", s3_path, opts.delete_after_upload);
-                let uploaded = s3.upload_file(path, &s3_path, &opts.bucket_name);
-                if uploaded.is_ok() && opts.delete_after_upload {
-                    fs::remove_file(path).unwrap();
-                }
+                // s3cmd is a blocking process
+                tokio::task::block_in_place(|| upload_crate_file(path, opts));
             }
             Ok(())
         }
@@ -394,3 +597,168 @@ pub fn download_crates_with_log(
         }
     }
 }
+
+fn upload_crate_file(path: &Path, opts: &CratesOptions) {
+    let s3 = S3cmd::default();
+    let s3_path = format!(
+        "crates{}",
+        path.to_str()
+            .unwrap()
+            .replace(opts.crates_path.to_str().unwrap(), "")
+    );
+    tracing::info!("s3_path: {}, {}", s3_path, opts.delete_after_upload);
+    let uploaded = s3.upload_file(path, &s3_path, &opts.bucket_name);
+    if uploaded.is_ok() && opts.delete_after_upload {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, sync::Arc};
+
+    use git2::{Repository, Signature};
+    use sha2::{Digest, Sha256};
+
+    use crate::config::{CratesConfig, ProxyConfig};
+    use crate::download::Downloader;
+    use crate::handler::index::{CrateIndex, SERVED_REF};
+    use crate::handler::retention::Retention;
+    use crate::server::owners::OwnerStore;
+
+    use super::{download, prune, CratesOptions};
+
+    fn commit_all(repo: &Repository) {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("freighter", "freighter@test").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "update",
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_advance_served_ref() {
+        let work_dir = env::temp_dir().join("freighter-test-advance-served-ref");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::create_dir_all(work_dir.join("log")).unwrap();
+        let content = b"foo crate";
+        fs::write(
+            index_path.join("3/f/foo"),
+            format!(
+                "{{\"name\":\"foo\",\"vers\":\"0.1.0\",\"deps\":[],\"cksum\":\"{:x}\",\"features\":{{}},\"yanked\":false}}\n",
+                Sha256::digest(content)
+            ),
+        )
+        .unwrap();
+        let repo = Repository::init(&index_path).unwrap();
+        commit_all(&repo);
+        let lockfile = work_dir.join("Cargo.lock");
+        fs::write(
+            &lockfile,
+            "[[package]]\nname = \"foo\"\nversion = \"0.1.0\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+        )
+        .unwrap();
+        let opts = CratesOptions {
+            config: CratesConfig {
+                serve_from_git: true,
+                lockfiles: vec![lockfile],
+                // nothing listens on the port, so the download fails
+                domain: "http://127.0.0.1:1".to_owned(),
+                ..Default::default()
+            },
+            index: CrateIndex::new("http://127.0.0.1:1", index_path.clone()),
+            crates_path: work_dir.join("crates"),
+            log_path: work_dir.join("log"),
+            downloader: Some(Downloader::new(&ProxyConfig::default(), 4).unwrap()),
+            ..Default::default()
+        };
+        let served = |repo: &Repository| repo.refname_to_id(SERVED_REF).ok();
+
+        // the crate failed to download, so the new index is not served
+        download(&opts).unwrap();
+        assert_eq!(served(&repo), None);
+        let log = fs::read_to_string(work_dir.join("log/error-crates.log")).unwrap();
+        assert!(log.contains("\"name\":\"foo\""));
+
+        // the crate is there now, the failure of the previous run doesn't hold the index back
+        let crate_file = Path::new("foo/foo-0.1.0.crate");
+        fs::create_dir_all(work_dir.join("crates/foo")).unwrap();
+        fs::write(work_dir.join("crates").join(crate_file), content).unwrap();
+        download(&opts).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        assert_eq!(served(&repo), Some(head));
+
+        // nothing is served from git if it's not enabled
+        repo.find_reference(SERVED_REF).unwrap().delete().unwrap();
+        let opts = CratesOptions {
+            config: CratesConfig {
+                serve_from_git: false,
+                ..opts.config.clone()
+            },
+            ..opts
+        };
+        download(&opts).unwrap();
+        assert_eq!(served(&repo), None);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_skips_published() {
+        let work_dir = env::temp_dir().join("freighter-test-prune-published");
+        let _ = fs::remove_dir_all(&work_dir);
+        let line = |name: &str, vers: &str| {
+            format!(
+                "{{\"name\":\"{}\",\"vers\":\"{}\",\"deps\":[],\"features\":{{}},\"yanked\":false}}\n",
+                name, vers
+            )
+        };
+        for name in ["foo", "bar"] {
+            fs::create_dir_all(work_dir.join("index/3").join(&name[..1])).unwrap();
+            fs::write(
+                work_dir.join("index/3").join(&name[..1]).join(name),
+                line(name, "0.1.0") + &line(name, "0.2.0"),
+            )
+            .unwrap();
+            fs::create_dir_all(work_dir.join("crates").join(name)).unwrap();
+            for vers in ["0.1.0", "0.2.0"] {
+                let file = format!("{}-{}.crate", name, vers);
+                fs::write(work_dir.join("crates").join(name).join(file), "crate").unwrap();
+            }
+        }
+        // bar is published locally
+        OwnerStore::new(work_dir.join("owners"))
+            .add("bar", &["alice".to_owned()])
+            .unwrap();
+        let opts = CratesOptions {
+            retention: Arc::new(Retention {
+                keep_latest: 1,
+                ..Default::default()
+            }),
+            index: CrateIndex::new("http://127.0.0.1:1", work_dir.join("index")),
+            crates_path: work_dir.join("crates"),
+            owners_path: work_dir.join("owners"),
+            ..Default::default()
+        };
+
+        prune(&opts, false).unwrap();
+        assert!(!work_dir.join("crates/foo/foo-0.1.0.crate").exists());
+        assert!(work_dir.join("crates/foo/foo-0.2.0.crate").exists());
+        assert!(work_dir.join("crates/bar/bar-0.1.0.crate").exists());
+        assert!(work_dir.join("crates/bar/bar-0.2.0.crate").exists());
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+}
@@ -5,13 +5,14 @@
 /// - [git2-rs](https://github.com/rust-lang/git2-rs)'s clone (example)[https://github.com/rust-lang/git2-rs/blob/master/examples/clone.rs].
 /// - [crates.io](https://github.com/rust-lang/crates.io)'s [structs](https://github.com/rust-lang/crates.io/blob/master/cargo-registry-index/lib.rs)
 ///
-use chrono::Utc;
+use chrono::{NaiveDate, Utc};
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use git2::{
     DiffFormat, DiffLine, DiffOptions, ErrorCode, FetchOptions, Object, ObjectType, Oid, Progress,
-    ProxyOptions, RemoteCallbacks, Repository, Sort,
+    ProxyOptions, RemoteCallbacks, Repository, Signature, Sort,
 };
 
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 use std::cell::RefCell;
@@ -22,9 +23,11 @@ use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, Mutex};
 
+use crate::config::ServerConfig;
 use crate::errors::FreightResult;
 
 use super::crates_file::{parse_index_and_download, CratesOptions};
+use super::search;
 
 /// `CrateIndex` is a wrapper `Git Repository` that crates-io index.
 ///
@@ -36,6 +39,52 @@ pub struct CrateIndex {
     pub path: PathBuf,
 }
 
+/// `IndexConfig` is the `config.json` in the root of the index, which tells cargo
+/// where to download crates and where the web api is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexConfig {
+    pub dl: String,
+    pub api: String,
+    #[serde(
+        rename = "auth-required",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub auth_required: bool,
+}
+
+impl IndexConfig {
+    pub fn new(server: &ServerConfig) -> Self {
+        let public_url = server.public_url.trim_end_matches('/');
+        IndexConfig {
+            dl: format!("{}/crates", public_url),
+            api: public_url.to_owned(),
+            auth_required: server.auth_required,
+        }
+    }
+}
+
+/// the commit served by sparse index when `serve_from_git` is enabled, it's advanced
+/// only after a sync has finished, so clients never see a half updated index
+pub const SERVED_REF: &str = "refs/freighter/served";
+
+/// a file read from the git objects of the index
+#[derive(Debug, Clone)]
+pub struct IndexBlob {
+    pub content: Vec<u8>,
+    pub oid: Oid,
+    // commit time in seconds since epoch
+    pub time: i64,
+}
+
+/// a synced commit of the index recorded in `{date}-record.log`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub commit: String,
+    // seconds since epoch when the commit is synced
+    pub timestamp: i64,
+}
+
 /// State contains the progress when download index file
 ///
 ///
@@ -82,13 +131,17 @@ impl CrateIndex {
         let commit = object.peel_to_commit()?;
         let fetch_commit = do_fetch(&repo, &[CrateIndex::REMOTE_BRANCH], &mut remote, opts)?;
 
-        self.save_commit_log(&opts.log_path, &commit.id(), &fetch_commit.id());
         tracing::info!(
             "commit id:{}, remote id :{}",
             commit.id(),
             &fetch_commit.id()
         );
-        do_merge(&repo, CrateIndex::REMOTE_BRANCH, fetch_commit)
+        do_merge(&repo, CrateIndex::REMOTE_BRANCH, fetch_commit)?;
+        // record the merged HEAD instead of the fetched commit, they differ when the local
+        // index has its own commits, and the snapshots must point to a local commit
+        let head = repo.head()?.peel_to_commit()?.id();
+        self.save_commit_log(&opts.log_path, &commit.id(), &head);
+        Ok(())
     }
 
     /// Clone the `CrateIndex` to a local directory.
@@ -150,7 +203,7 @@ impl CrateIndex {
         file_name.push('-');
         file_name.push_str("record.log");
         let file_name = &log_path.join(file_name);
-        let mut f = match OpenOptions::new().write(true).append(true).open(file_name) {
+        let mut f = match OpenOptions::new().append(true).open(file_name) {
             Ok(f) => f,
             Err(err) => match err.kind() {
                 ErrorKind::NotFound => {
@@ -175,11 +228,7 @@ fn print(state: &mut State) {
     let stats = state.progress.as_ref().unwrap();
     let network_pct = (100 * stats.received_objects()) / stats.total_objects();
     let index_pct = (100 * stats.indexed_objects()) / stats.total_objects();
-    let co_pct = if state.total > 0 {
-        (100 * state.current) / state.total
-    } else {
-        0
-    };
+    let co_pct = (100 * state.current).checked_div(state.total).unwrap_or(0);
 
     let kb = stats.received_bytes() / 1024;
 
@@ -248,7 +297,9 @@ pub fn pull(opts: &CratesOptions) -> FreightResult {
     } else {
         index.git_clone(opts).unwrap();
     }
-    Ok(())
+    // the config.json of crates.io points to crates.io, replace it with our own
+    write_index_config(&index.path, &IndexConfig::new(&opts.server))?;
+    search::rebuild_search_index(&index.path, &opts.search_path)
 }
 
 /// get repo from path
@@ -268,6 +319,164 @@ pub fn get_repo(path: PathBuf) -> Repository {
     }
 }
 
+/// commit a changed or removed index file to the index repository so git clients can fetch
+/// it, the repository is initialized if the index path is not a git repository yet
+pub fn commit_index_file(
+    index_path: &Path,
+    file: &Path,
+    message: &str,
+) -> Result<(), anyhow::Error> {
+    let repo = match Repository::open(index_path) {
+        Ok(repo) => repo,
+        Err(e) if e.code() == ErrorCode::NotFound => Repository::init(index_path)?,
+        Err(e) => return Err(e.into()),
+    };
+    let mut index = repo.index()?;
+    if index_path.join(file).exists() {
+        index.add_path(file)?;
+    } else {
+        index.remove_path(file)?;
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let sig = repo
+        .signature()
+        .or_else(|_| Signature::now("freighter", "freighter@localhost"))?;
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    let commit = repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)?;
+    tracing::info!("index commit {}: {}", commit, message);
+
+    // a local commit needs no download, so it's served at once if the index is up to date
+    if let (Ok(served), Some(parent)) = (repo.refname_to_id(SERVED_REF), &parent) {
+        if served == parent.id() {
+            repo.reference(SERVED_REF, commit, true, message)?;
+        }
+    }
+    Ok(())
+}
+
+/// unstage the index file, so a change left by a failed commit isn't picked up by the next one
+pub fn unstage_index_file(index_path: &Path, file: &Path) -> Result<(), anyhow::Error> {
+    let repo = Repository::open(index_path)?;
+    let head = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?.into_object()),
+        Err(_) => None,
+    };
+    repo.reset_default(head.as_ref(), [file])?;
+    Ok(())
+}
+
+/// point the served commit to HEAD of the index
+pub fn advance_served_ref(index_path: &Path) -> Result<Oid, anyhow::Error> {
+    let repo = Repository::open(index_path)?;
+    let head = repo.head()?.peel_to_commit()?.id();
+    repo.reference(SERVED_REF, head, true, "sync finished")?;
+    tracing::info!("advance served index to {}", head);
+    Ok(head)
+}
+
+/// read all synced commits from the record logs, sorted by sync time
+pub fn read_snapshots(log_path: &Path) -> Result<Vec<Snapshot>, anyhow::Error> {
+    let mut snapshots = Vec::new();
+    let entries = match fs::read_dir(log_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(snapshots),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let is_record = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with("-record.log"));
+        if !is_record {
+            continue;
+        }
+        // each line is `from_commit,to_commit,timestamp`
+        for line in fs::read_to_string(&path)?.lines() {
+            let fields: Vec<&str> = line.split(',').collect();
+            if let [_, to_commit, timestamp] = fields[..] {
+                if let Ok(timestamp) = timestamp.trim().parse() {
+                    snapshots.push(Snapshot {
+                        commit: to_commit.to_owned(),
+                        timestamp,
+                    });
+                }
+            }
+        }
+    }
+    snapshots.sort_by_key(|s| s.timestamp);
+    Ok(snapshots)
+}
+
+/// find the snapshot by commit id (at least 7 characters) or by date, a date means the
+/// last snapshot synced on or before that day
+pub fn find_snapshot<'a>(snapshots: &'a [Snapshot], id: &str) -> Option<&'a Snapshot> {
+    if let Ok(date) = NaiveDate::parse_from_str(id, "%Y-%m-%d") {
+        let end_of_day = date.succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+        return snapshots.iter().rev().find(|s| s.timestamp < end_of_day);
+    }
+    if id.len() < 7 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let id = id.to_lowercase();
+    snapshots.iter().rev().find(|s| s.commit.starts_with(&id))
+}
+
+/// read a file of the index at the served commit, HEAD is used before the first sync
+pub fn read_served_blob(
+    index_path: &Path,
+    path: &Path,
+) -> Result<Option<IndexBlob>, anyhow::Error> {
+    let repo = Repository::open(index_path)?;
+    let rev = match repo.find_reference(SERVED_REF) {
+        Ok(_) => SERVED_REF,
+        Err(e) if e.code() == ErrorCode::NotFound => "HEAD",
+        Err(e) => return Err(e.into()),
+    };
+    read_index_blob(index_path, rev, path)
+}
+
+/// read a file of the index at the revision, return None if the file doesn't exist
+pub fn read_index_blob(
+    index_path: &Path,
+    rev: &str,
+    path: &Path,
+) -> Result<Option<IndexBlob>, anyhow::Error> {
+    let repo = Repository::open(index_path)?;
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    let entry = match commit.tree()?.get_path(path) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = match entry.to_object(&repo)?.into_blob() {
+        Ok(blob) => blob,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(IndexBlob {
+        content: blob.content().to_vec(),
+        oid: blob.id(),
+        time: commit.time().seconds(),
+    }))
+}
+
+/// write the `config.json` to the index and commit it, nothing is done if it's up to date
+pub fn write_index_config(index_path: &Path, config: &IndexConfig) -> Result<(), anyhow::Error> {
+    let config_file = index_path.join("config.json");
+    let content = serde_json::to_string_pretty(config)?;
+    if fs::read_to_string(&config_file).is_ok_and(|old| old == content) {
+        return Ok(());
+    }
+    fs::create_dir_all(index_path)?;
+    fs::write(&config_file, content)?;
+    commit_index_file(index_path, Path::new("config.json"), "Update config.json")
+}
+
 pub fn git2_diff(
     options: &CratesOptions,
     from_oid: &str,
@@ -302,9 +511,7 @@ fn handle_diff_line(line: DiffLine, opts: &CratesOptions, err_record: &Arc<Mutex
         return true;
     }
     let index_path = opts.index.path.join(path_suffix);
-    opts.thread_pool.scope(|s| {
-        parse_index_and_download(&index_path, opts, s, err_record).unwrap();
-    });
+    parse_index_and_download(&index_path, opts, err_record).unwrap();
     true
 }
 
@@ -504,7 +711,20 @@ fn do_merge<'a>(
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+    };
+
+    use git2::{Repository, Signature};
+
+    use crate::config::ServerConfig;
+    use crate::handler::crates_file::CratesOptions;
+
+    use super::{
+        advance_served_ref, commit_index_file, find_snapshot, read_served_blob, read_snapshots,
+        write_index_config, CrateIndex, IndexConfig,
+    };
 
     // use crate::handler::crates_file::CratesOptions;
 
@@ -513,8 +733,7 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("data/tests/fixtures/");
 
-        let _ =
-            super::CrateIndex::new("https://github.com/rust-lang/crates.io-index.git", path);
+        let _ = super::CrateIndex::new("https://github.com/rust-lang/crates.io-index.git", path);
         // index.git_clone(&mut CratesOptions::default()).unwrap();
     }
 
@@ -523,7 +742,147 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("data/tests/fixtures/");
 
-        let _ =
-            super::CrateIndex::new("https://github.com/rust-lang/crates.io-index.git", path);
+        let _ = super::CrateIndex::new("https://github.com/rust-lang/crates.io-index.git", path);
+    }
+
+    #[test]
+    fn test_write_index_config() {
+        let index_path = env::temp_dir().join("freighter-test-index-config");
+        let _ = fs::remove_dir_all(&index_path);
+        let config = IndexConfig::new(&ServerConfig::default());
+        write_index_config(&index_path, &config).unwrap();
+        // unchanged config doesn't create a new commit
+        write_index_config(&index_path, &config).unwrap();
+
+        let content = fs::read_to_string(index_path.join("config.json")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<IndexConfig>(&content).unwrap(),
+            config
+        );
+        let repo = Repository::open(&index_path).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("Update config.json"));
+        assert_eq!(head.parent_count(), 0);
+        fs::remove_dir_all(index_path).unwrap();
+    }
+
+    #[test]
+    fn test_served_ref() {
+        let index_path = env::temp_dir().join("freighter-test-served-ref");
+        let _ = fs::remove_dir_all(&index_path);
+        let file = Path::new("3/f/foo");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::write(index_path.join(file), "v1\n").unwrap();
+        commit_index_file(&index_path, file, "Updating crate `foo#0.1.0`").unwrap();
+        // HEAD is served before the first sync
+        let blob = read_served_blob(&index_path, file).unwrap().unwrap();
+        assert_eq!(blob.content, b"v1\n");
+        advance_served_ref(&index_path).unwrap();
+
+        // a commit pulled from upstream is not served until the sync finished
+        fs::write(index_path.join(file), "v1\nv2\n").unwrap();
+        let repo = Repository::open(&index_path).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(file).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let sig = Signature::now("upstream", "upstream@localhost").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "pull", &tree, &[&head])
+            .unwrap();
+        let blob = read_served_blob(&index_path, file).unwrap().unwrap();
+        assert_eq!(blob.content, b"v1\n");
+
+        advance_served_ref(&index_path).unwrap();
+        let blob = read_served_blob(&index_path, file).unwrap().unwrap();
+        assert_eq!(blob.content, b"v1\nv2\n");
+
+        // local commits are served at once
+        fs::write(index_path.join(file), "v1\nv2\nv3\n").unwrap();
+        commit_index_file(&index_path, file, "Updating crate `foo#0.3.0`").unwrap();
+        let blob = read_served_blob(&index_path, file).unwrap().unwrap();
+        assert_eq!(blob.content, b"v1\nv2\nv3\n");
+        assert!(read_served_blob(&index_path, Path::new("3/b/bar"))
+            .unwrap()
+            .is_none());
+        fs::remove_dir_all(index_path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let log_path = env::temp_dir().join("freighter-test-snapshots");
+        let _ = fs::remove_dir_all(&log_path);
+        fs::create_dir_all(&log_path).unwrap();
+        // 2024-01-01T12:00:00Z and 2024-01-02T12:00:00Z
+        fs::write(
+            log_path.join("2024-01-02-record.log"),
+            "bbb,ccccccc2,1704196800\n",
+        )
+        .unwrap();
+        fs::write(
+            log_path.join("2024-01-01-record.log"),
+            "aaa,ccccccc1,1704110400\n",
+        )
+        .unwrap();
+        fs::write(log_path.join("error-crates.log"), "foo,0.1.0\n").unwrap();
+
+        let snapshots = read_snapshots(&log_path).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].commit, "ccccccc1");
+        assert_eq!(
+            find_snapshot(&snapshots, "2024-01-01").unwrap().commit,
+            "ccccccc1"
+        );
+        assert_eq!(
+            find_snapshot(&snapshots, "2024-03-01").unwrap().commit,
+            "ccccccc2"
+        );
+        assert!(find_snapshot(&snapshots, "2023-12-31").is_none());
+        assert_eq!(
+            find_snapshot(&snapshots, "CCCCCCC2").unwrap().commit,
+            "ccccccc2"
+        );
+        assert!(find_snapshot(&snapshots, "ccc").is_none());
+        fs::remove_dir_all(log_path).unwrap();
+    }
+
+    #[test]
+    fn test_pull_records_merged_head() {
+        let work_dir = env::temp_dir().join("freighter-test-pull-merged-head");
+        let _ = fs::remove_dir_all(&work_dir);
+        let upstream = work_dir.join("upstream");
+        let file = Path::new("3/f/foo");
+        fs::create_dir_all(upstream.join("3/f")).unwrap();
+        let mut init = git2::RepositoryInitOptions::new();
+        init.initial_head("master");
+        Repository::init_opts(&upstream, &init).unwrap();
+        fs::write(upstream.join(file), "v1\n").unwrap();
+        commit_index_file(&upstream, file, "Updating crate `foo#0.1.0`").unwrap();
+
+        let local = work_dir.join("local");
+        let repo = Repository::clone(upstream.to_str().unwrap(), &local).unwrap();
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("user.name", "freighter").unwrap();
+        git_config
+            .set_str("user.email", "freighter@localhost")
+            .unwrap();
+        // the local index has its own commit, so the pull makes a merge commit
+        write_index_config(&local, &IndexConfig::new(&ServerConfig::default())).unwrap();
+        fs::write(upstream.join(file), "v1\nv2\n").unwrap();
+        commit_index_file(&upstream, file, "Updating crate `foo#0.2.0`").unwrap();
+
+        let opts = CratesOptions {
+            no_progressbar: true,
+            log_path: work_dir.join("log"),
+            ..Default::default()
+        };
+        CrateIndex::new(&format!("file://{}", upstream.display()), local.clone())
+            .git_pull(&opts)
+            .unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 2);
+        assert_eq!(fs::read_to_string(local.join(file)).unwrap(), "v1\nv2\n");
+        let snapshots = read_snapshots(&work_dir.join("log")).unwrap();
+        assert_eq!(snapshots.last().unwrap().commit, head.id().to_string());
+        fs::remove_dir_all(work_dir).unwrap();
     }
 }
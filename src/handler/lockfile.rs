@@ -0,0 +1,107 @@
+//! read the crates.io packages locked by `Cargo.lock` files, so only the dependency closure of
+//! some projects is mirrored instead of the whole crates.io
+//!
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// the sources of crates.io packages in `Cargo.lock`
+pub const CRATES_IO_SOURCES: &[&str] = &[
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+#[derive(Deserialize, Debug)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    // path dependencies and workspace members have no source
+    source: Option<String>,
+}
+
+/// return the locked versions of each crates.io package, keyed by crate name
+pub fn read_locked_crates(
+    paths: &[PathBuf],
+) -> Result<BTreeMap<String, BTreeSet<String>>, anyhow::Error> {
+    let mut crates: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for path in paths {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read lockfile {}", path.display()))?;
+        let lockfile: Lockfile = toml::from_str(&content)
+            .with_context(|| format!("invalid lockfile {}", path.display()))?;
+        for package in lockfile.package {
+            if package
+                .source
+                .as_deref()
+                .is_some_and(|source| CRATES_IO_SOURCES.contains(&source))
+            {
+                crates
+                    .entry(package.name)
+                    .or_default()
+                    .insert(package.version);
+            }
+        }
+    }
+    Ok(crates)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::read_locked_crates;
+
+    #[test]
+    fn test_read_locked_crates() {
+        let work_dir = env::temp_dir().join("freighter-test-lockfile");
+        let _ = fs::remove_dir_all(&work_dir);
+        fs::create_dir_all(&work_dir).unwrap();
+        let lockfile = r#"
+version = 3
+
+[[package]]
+name = "demo"
+version = "0.1.0"
+dependencies = ["serde"]
+
+[[package]]
+name = "serde"
+version = "1.0.195"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "63261df402c67811e9ac6def069e4786148c4563f4b50fd4bf30aa370d626b02"
+
+[[package]]
+name = "tokio"
+version = "1.35.1"
+source = "git+https://github.com/tokio-rs/tokio#abc"
+"#;
+        fs::write(work_dir.join("a.lock"), lockfile).unwrap();
+        fs::write(
+            work_dir.join("b.lock"),
+            lockfile.replace("1.0.195", "1.0.196"),
+        )
+        .unwrap();
+
+        let crates =
+            read_locked_crates(&[work_dir.join("a.lock"), work_dir.join("b.lock")]).unwrap();
+        assert_eq!(crates.len(), 1);
+        assert_eq!(
+            crates["serde"].iter().collect::<Vec<_>>(),
+            vec!["1.0.195", "1.0.196"]
+        );
+        assert!(read_locked_crates(&[work_dir.join("c.lock")]).is_err());
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+}
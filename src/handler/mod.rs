@@ -8,7 +8,12 @@
 pub mod channel;
 pub mod crates_file;
 pub mod index;
+pub mod lockfile;
+pub mod policy;
+pub mod resolver;
+pub mod retention;
 pub mod rustup;
+pub mod search;
 
 #[derive(Clone, Default, Debug)]
 pub enum DownloadMode {
@@ -0,0 +1,136 @@
+//! crates policy decides which crates and versions are mirrored and served, it is a
+//! toml file referenced by `policy_path` of the crates config:
+//!
+//! ```toml
+//! # only crates matched by allow rules are mirrored, all crates are allowed if it's empty
+//! [[allow]]
+//! name = "serde"
+//!
+//! # denied versions are never downloaded, hidden from the index and rejected with 403
+//! [[deny]]
+//! name = "rustdecimal"
+//! version = "*"
+//! reason = "malware"
+//! ```
+//!
+//! The `version` of a rule is a semver requirement, all versions are matched if it's omitted.
+//! Unlike cargo, a requirement without `=` matches the pre-releases of the versions it matches.
+//!
+
+use std::{fs, path::PathBuf};
+
+use semver::{Op, Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<Rule>,
+    #[serde(default)]
+    pub deny: Vec<Rule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub version: Option<String>,
+    // why the crate is denied, it's shown to the client
+    pub reason: Option<String>,
+}
+
+impl Rule {
+    fn matches(&self, name: &str, version: &str) -> bool {
+        if normalize(&self.name) != normalize(name) {
+            return false;
+        }
+        let req = match &self.version {
+            Some(req) => req,
+            None => return true,
+        };
+        match (VersionReq::parse(req), Version::parse(version)) {
+            // `*` and ranges don't match pre-release versions in semver, but a rule should,
+            // an exact `=` requirement still means the very version
+            (Ok(req), Ok(mut version)) => {
+                req.matches(&version)
+                    || (!req.comparators.iter().any(|c| c.op == Op::Exact) && {
+                        version.pre = semver::Prerelease::EMPTY;
+                        req.matches(&version)
+                    })
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Policy {
+    /// load policy from file, everything is allowed if the path is not set
+    pub fn load(path: &Option<PathBuf>) -> Result<Policy, anyhow::Error> {
+        match path {
+            Some(path) => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            None => Ok(Policy::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// return the denied rule if the version is not allowed
+    pub fn check(&self, name: &str, version: &str) -> Result<(), String> {
+        if let Some(rule) = self.deny.iter().find(|r| r.matches(name, version)) {
+            return Err(match &rule.reason {
+                Some(reason) => format!("`{}@{}` is denied: {}", name, version, reason),
+                None => format!("`{}@{}` is denied by policy", name, version),
+            });
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| r.matches(name, version)) {
+            return Err(format!("`{}@{}` is not in the allowlist", name, version));
+        }
+        Ok(())
+    }
+
+    pub fn is_allowed(&self, name: &str, version: &str) -> bool {
+        self.check(name, version).is_ok()
+    }
+}
+
+// crates name are case insensitive and treat '-' and '_' as the same
+fn normalize(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+
+    #[test]
+    fn test_policy() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[allow]]
+            name = "serde"
+            [[allow]]
+            name = "rand"
+            version = "^0.8"
+
+            [[deny]]
+            name = "rand"
+            version = "=0.8.1"
+            reason = "broken"
+            "#,
+        )
+        .unwrap();
+        assert!(policy.is_allowed("serde", "1.0.0-beta.1"));
+        assert!(policy.is_allowed("rand", "0.8.5"));
+        assert!(!policy.is_allowed("rand", "0.7.3"));
+        assert_eq!(
+            policy.check("rand", "0.8.1").unwrap_err(),
+            "`rand@0.8.1` is denied: broken"
+        );
+        // the exact rule doesn't deny the pre-releases of the version
+        assert!(policy.is_allowed("rand", "0.8.1-rc.1"));
+        assert!(policy.is_allowed("rand", "0.8.6-alpha.1"));
+        assert!(!policy.is_allowed("tokio", "1.0.0"));
+        assert!(Policy::default().is_allowed("tokio", "1.0.0"));
+    }
+}
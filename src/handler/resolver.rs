@@ -0,0 +1,584 @@
+//! resolve the dependency closure of root crates over the local index, so only the crates
+//! which could be used by them are mirrored.
+//!
+//! Every version matching a requirement is included, not only the newest one, because cargo
+//! may choose any of them depending on the other crates in a lockfile. Yanked versions are
+//! skipped since cargo never chooses them for a new lockfile.
+//!
+//! The features of each version are tracked, so an optional dependency is included only if a
+//! feature enabling it is requested by any dependent. Dev dependencies are only needed to test
+//! a crate, they are included for root crates when `include_dev_dependencies` is enabled.
+//!
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind},
+    path::PathBuf,
+};
+
+use semver::{Version, VersionReq};
+
+use crate::config::{CratesConfig, RootCrate};
+
+use super::{
+    crates_file::{DependencyKind, IndexFile},
+    utils,
+};
+
+/// versions of each crate, keyed by crate name
+pub type ResolvedCrates = BTreeMap<String, BTreeSet<String>>;
+
+// the features requested for a version by all its dependents
+#[derive(Debug, Default)]
+struct Activation {
+    features: BTreeSet<String>,
+    default_features: bool,
+    is_root: bool,
+}
+
+pub struct Resolver {
+    index_path: PathBuf,
+    include_dev: bool,
+    targets: Vec<String>,
+    // parsed index files keyed by lowercase crate name, empty if the crate is not in the index
+    index: HashMap<String, Vec<IndexFile>>,
+    resolved: BTreeMap<(String, String), Activation>,
+}
+
+impl Resolver {
+    pub fn new(index_path: PathBuf, config: &CratesConfig) -> Self {
+        Resolver {
+            index_path,
+            include_dev: config.include_dev_dependencies,
+            targets: config.targets.clone(),
+            index: HashMap::new(),
+            resolved: BTreeMap::new(),
+        }
+    }
+
+    /// compute the transitive closure of the root crates
+    pub fn resolve(&mut self, roots: &[RootCrate]) -> Result<ResolvedCrates, anyhow::Error> {
+        let mut queue = VecDeque::new();
+        for root in roots {
+            let req = VersionReq::parse(&root.req)?;
+            let features = root.features.iter().cloned().collect();
+            self.request(
+                &root.name,
+                &req,
+                features,
+                root.default_features,
+                true,
+                &mut queue,
+            )?;
+        }
+        // a version is processed again whenever new features are requested for it
+        while let Some(key) = queue.pop_front() {
+            self.activate_dependencies(&key, &mut queue)?;
+        }
+
+        let mut crates = ResolvedCrates::new();
+        for (name, vers) in self.resolved.keys() {
+            crates.entry(name.clone()).or_default().insert(vers.clone());
+        }
+        Ok(crates)
+    }
+
+    fn index_files(&mut self, name: &str) -> Result<&Vec<IndexFile>, anyhow::Error> {
+        let name = name.to_lowercase();
+        if !self.index.contains_key(&name) {
+            let index_file = self.index_path.join(utils::index_suffix(&name));
+            let mut files = Vec::new();
+            match File::open(&index_file) {
+                Ok(f) => {
+                    for line in BufReader::new(f).lines() {
+                        files.push(serde_json::from_str(&line?)?);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    tracing::warn!("crate {} is not found in the index", name);
+                }
+                Err(err) => return Err(err.into()),
+            }
+            self.index.insert(name.clone(), files);
+        }
+        Ok(&self.index[&name])
+    }
+
+    // request the versions of a crate matching the requirement with the features
+    fn request(
+        &mut self,
+        name: &str,
+        req: &VersionReq,
+        features: BTreeSet<String>,
+        default_features: bool,
+        is_root: bool,
+        queue: &mut VecDeque<(String, String)>,
+    ) -> Result<(), anyhow::Error> {
+        let matched: Vec<(String, String)> = self
+            .index_files(name)?
+            .iter()
+            .filter(|file| !file.yanked.unwrap_or(false))
+            .filter(|file| Version::parse(&file.vers).is_ok_and(|v| req.matches(&v)))
+            .map(|file| (file.name.clone(), file.vers.clone()))
+            .collect();
+
+        for key in matched {
+            let is_new = !self.resolved.contains_key(&key);
+            let activation = self.resolved.entry(key.clone()).or_default();
+            let mut changed = is_new;
+            for feature in &features {
+                changed |= activation.features.insert(feature.clone());
+            }
+            if default_features && !activation.default_features {
+                activation.default_features = true;
+                changed = true;
+            }
+            if is_root && !activation.is_root {
+                activation.is_root = true;
+                changed = true;
+            }
+            if changed {
+                queue.push_back(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn activate_dependencies(
+        &mut self,
+        key: &(String, String),
+        queue: &mut VecDeque<(String, String)>,
+    ) -> Result<(), anyhow::Error> {
+        let activation = &self.resolved[key];
+        let (is_root, default_features) = (activation.is_root, activation.default_features);
+        let mut enabled = activation.features.clone();
+
+        // the index file is always loaded by `request` before
+        let file = match self.index[&key.0.to_lowercase()]
+            .iter()
+            .find(|file| file.vers == key.1)
+        {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let feature_map: BTreeMap<&String, &Vec<String>> = file
+            .features
+            .iter()
+            .chain(file.features2.iter().flatten())
+            .collect();
+        if default_features && feature_map.contains_key(&"default".to_owned()) {
+            enabled.insert("default".to_owned());
+        }
+
+        // expand the features into the optional dependencies and features of dependencies
+        let mut active_deps = HashSet::new();
+        let mut dep_features: HashMap<String, BTreeSet<String>> = HashMap::new();
+        let mut weak_features = Vec::new();
+        let mut stack: Vec<String> = enabled.into_iter().collect();
+        let mut seen = HashSet::new();
+        while let Some(feature) = stack.pop() {
+            if !seen.insert(feature.clone()) {
+                continue;
+            }
+            let values = match feature_map.get(&feature) {
+                Some(values) => values,
+                // the implicit feature of an optional dependency
+                None => {
+                    active_deps.insert(feature);
+                    continue;
+                }
+            };
+            for value in values.iter() {
+                if let Some(dep) = value.strip_prefix("dep:") {
+                    active_deps.insert(dep.to_owned());
+                } else if let Some((dep, dep_feature)) = value.split_once('/') {
+                    match dep.strip_suffix('?') {
+                        // `dep?/feature` doesn't enable the optional dependency
+                        Some(dep) => weak_features.push((dep.to_owned(), dep_feature.to_owned())),
+                        None => {
+                            active_deps.insert(dep.to_owned());
+                            dep_features
+                                .entry(dep.to_owned())
+                                .or_default()
+                                .insert(dep_feature.to_owned());
+                        }
+                    }
+                } else {
+                    stack.push(value.clone());
+                }
+            }
+        }
+        for (dep, feature) in weak_features {
+            if active_deps.contains(&dep) {
+                dep_features.entry(dep).or_default().insert(feature);
+            }
+        }
+
+        let mut requests = Vec::new();
+        for dep in &file.deps {
+            let kind = dep.kind.unwrap_or(DependencyKind::Normal);
+            if kind == DependencyKind::Dev && !(self.include_dev && is_root) {
+                continue;
+            }
+            if dep.optional && !active_deps.contains(&dep.name) {
+                continue;
+            }
+            // crates from other registries are not mirrored
+            if dep.registry.is_some() || !self.target_matches(dep.target.as_deref()) {
+                continue;
+            }
+            let req = match VersionReq::parse(&dep.req) {
+                Ok(req) => req,
+                Err(err) => {
+                    tracing::warn!("invalid req {} of {}: {}", dep.req, dep.name, err);
+                    continue;
+                }
+            };
+            let mut features: BTreeSet<String> = dep.features.iter().cloned().collect();
+            features.extend(dep_features.get(&dep.name).into_iter().flatten().cloned());
+            // the dependency may be renamed, `package` is the real crate name
+            let package = dep.package.clone().unwrap_or_else(|| dep.name.clone());
+            requests.push((package, req, features, dep.default_features));
+        }
+        for (package, req, features, default_features) in requests {
+            self.request(&package, &req, features, default_features, false, queue)?;
+        }
+        Ok(())
+    }
+
+    // platform specific dependencies are included if they may be used by any of the targets
+    fn target_matches(&self, target: Option<&str>) -> bool {
+        let target = match target {
+            Some(target) if !self.targets.is_empty() => target,
+            _ => return true,
+        };
+        self.targets.iter().any(|triple| {
+            match target
+                .strip_prefix("cfg(")
+                .and_then(|t| t.strip_suffix(')'))
+            {
+                Some(expr) => eval_cfg(expr, &TargetCfg::new(triple)) != Some(false),
+                None => target == triple,
+            }
+        })
+    }
+}
+
+/// the cfg values of a target which can be known from its triple
+#[derive(Debug)]
+struct TargetCfg {
+    arch: String,
+    vendor: String,
+    os: String,
+    env: String,
+    family: Option<&'static str>,
+    pointer_width: &'static str,
+}
+
+impl TargetCfg {
+    fn new(triple: &str) -> Self {
+        let parts: Vec<&str> = triple.split('-').collect();
+        let arch = match parts[0] {
+            "i386" | "i586" | "i686" => "x86",
+            arch if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+            arch if arch.starts_with("riscv64") => "riscv64",
+            arch if arch.starts_with("riscv32") => "riscv32",
+            arch if arch.starts_with("powerpc64") => "powerpc64",
+            arch => arch,
+        };
+        let vendor = if parts.len() > 2 { parts[1] } else { "unknown" };
+        let os = parts
+            .iter()
+            .skip(1)
+            .find_map(|part| match *part {
+                "darwin" => Some("macos"),
+                "linux" | "windows" | "android" | "ios" | "freebsd" | "netbsd" | "openbsd"
+                | "dragonfly" | "solaris" | "illumos" | "fuchsia" | "redox" | "haiku" | "wasi"
+                | "emscripten" | "none" => Some(part),
+                _ => None,
+            })
+            .unwrap_or("unknown");
+        // android is both os and env in the triple
+        let os = if parts.contains(&"android") || parts.contains(&"androideabi") {
+            "android"
+        } else {
+            os
+        };
+        let env = parts
+            .last()
+            .and_then(|last| {
+                ["gnu", "musl", "msvc", "sgx", "uclibc", "newlib"]
+                    .into_iter()
+                    .find(|env| last.starts_with(env))
+            })
+            .unwrap_or("");
+        let family = match os {
+            "windows" => Some("windows"),
+            "linux" | "android" | "macos" | "ios" | "freebsd" | "netbsd" | "openbsd"
+            | "dragonfly" | "solaris" | "illumos" | "fuchsia" | "redox" | "haiku" => Some("unix"),
+            _ if arch.starts_with("wasm") => Some("wasm"),
+            _ => None,
+        };
+        let pointer_width = match arch {
+            "x86_64" | "aarch64" | "riscv64" | "powerpc64" | "mips64" | "s390x" | "sparc64"
+            | "loongarch64" | "wasm64" => "64",
+            _ => "32",
+        };
+        TargetCfg {
+            arch: arch.to_owned(),
+            vendor: vendor.to_owned(),
+            os: os.to_owned(),
+            env: env.to_owned(),
+            family,
+            pointer_width,
+        }
+    }
+
+    // None if the value can't be known from the triple, like `target_feature`
+    fn matches(&self, key: &str, value: Option<&str>) -> Option<bool> {
+        match (key, value) {
+            ("unix", None) => Some(self.family == Some("unix")),
+            ("windows", None) => Some(self.family == Some("windows")),
+            ("target_family", Some(value)) => Some(self.family == Some(value)),
+            ("target_arch", Some(value)) => Some(self.arch == value),
+            ("target_vendor", Some(value)) => Some(self.vendor == value),
+            ("target_os", Some(value)) => Some(self.os == value),
+            ("target_env", Some(value)) => Some(self.env == value),
+            ("target_pointer_width", Some(value)) => Some(self.pointer_width == value),
+            _ => None,
+        }
+    }
+}
+
+/// evaluate the expression inside `cfg(...)` for the target, None if the result is unknown
+fn eval_cfg(expr: &str, cfg: &TargetCfg) -> Option<bool> {
+    let tokens = tokenize(expr)?;
+    let mut pos = 0;
+    let result = parse_predicate(&tokens, &mut pos, cfg)?;
+    (pos == tokens.len()).then_some(result)?
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Str(&'a str),
+    Open,
+    Close,
+    Comma,
+    Equal,
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '(' | ')' | ',' | '=' => {
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    ',' => Token::Comma,
+                    _ => Token::Equal,
+                });
+                1
+            }
+            '"' => {
+                let end = rest[1..].find('"')?;
+                tokens.push(Token::Str(&rest[1..end + 1]));
+                end + 2
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                tokens.push(Token::Ident(&rest[..end]));
+                end
+            }
+            _ => return None,
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+// the outer Option is a parse error, the inner one is an unknown result
+fn parse_predicate(tokens: &[Token], pos: &mut usize, cfg: &TargetCfg) -> Option<Option<bool>> {
+    let ident = match tokens.get(*pos)? {
+        Token::Ident(ident) => *ident,
+        _ => return None,
+    };
+    *pos += 1;
+    match tokens.get(*pos) {
+        Some(Token::Open) => {
+            *pos += 1;
+            let mut values = Vec::new();
+            while tokens.get(*pos) != Some(&Token::Close) {
+                values.push(parse_predicate(tokens, pos, cfg)?);
+                if tokens.get(*pos) == Some(&Token::Comma) {
+                    *pos += 1;
+                }
+            }
+            *pos += 1;
+            match ident {
+                "all" if values.contains(&Some(false)) => Some(Some(false)),
+                "all" => Some(values.iter().all(|v| v.is_some()).then_some(true)),
+                "any" if values.contains(&Some(true)) => Some(Some(true)),
+                "any" => Some(values.iter().all(|v| v.is_some()).then_some(false)),
+                "not" if values.len() == 1 => Some(values[0].map(|v| !v)),
+                _ => None,
+            }
+        }
+        Some(Token::Equal) => match tokens.get(*pos + 1)? {
+            Token::Str(value) => {
+                *pos += 2;
+                Some(cfg.matches(ident, Some(value)))
+            }
+            _ => None,
+        },
+        _ => Some(cfg.matches(ident, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::config::{CratesConfig, RootCrate};
+
+    use super::{eval_cfg, Resolver, TargetCfg};
+
+    #[test]
+    fn test_eval_cfg() {
+        let linux = TargetCfg::new("x86_64-unknown-linux-gnu");
+        let windows = TargetCfg::new("x86_64-pc-windows-msvc");
+        assert_eq!(eval_cfg("unix", &linux), Some(true));
+        assert_eq!(eval_cfg("unix", &windows), Some(false));
+        assert_eq!(
+            eval_cfg(r#"all(target_os = "linux", target_env = "gnu")"#, &linux),
+            Some(true)
+        );
+        assert_eq!(
+            eval_cfg(r#"any(windows, target_arch = "wasm32")"#, &linux),
+            Some(false)
+        );
+        assert_eq!(eval_cfg("not(windows)", &windows), Some(false));
+        // target features can't be known from the triple
+        assert_eq!(eval_cfg(r#"target_feature = "sse2""#, &linux), None);
+        assert_eq!(
+            eval_cfg(r#"all(windows, target_feature = "sse2")"#, &linux),
+            Some(false)
+        );
+        assert_eq!(eval_cfg("all(unix", &linux), None);
+        assert_eq!(
+            eval_cfg(
+                r#"target_os = "macos""#,
+                &TargetCfg::new("aarch64-apple-darwin")
+            ),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let index_path = env::temp_dir().join("freighter-test-resolver");
+        let _ = fs::remove_dir_all(&index_path);
+        let crates = [
+            (
+                "1/a",
+                vec![
+                    r#"{"name":"a","vers":"1.0.0","deps":[
+                    {"name":"b","req":"^1","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"},
+                    {"name":"c","req":"*","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"},
+                    {"name":"d","req":"*","features":[],"optional":false,"default_features":true,"target":null,"kind":"dev"},
+                    {"name":"win","req":"*","features":[],"optional":false,"default_features":true,"target":"cfg(windows)","kind":"build"},
+                    {"name":"f","req":"*","features":[],"optional":false,"default_features":true,"target":"cfg(unix)","kind":"normal","package":"real-f"}
+                ],"cksum":"","features":{"full":["c/extra"]},"yanked":false}"#,
+                ],
+            ),
+            (
+                "1/b",
+                vec![
+                    r#"{"name":"b","vers":"1.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                    r#"{"name":"b","vers":"1.1.0","deps":[],"cksum":"","features":{},"yanked":true}"#,
+                    r#"{"name":"b","vers":"1.2.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                    r#"{"name":"b","vers":"2.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                ],
+            ),
+            (
+                "1/c",
+                vec![
+                    r#"{"name":"c","vers":"1.0.0","deps":[
+                    {"name":"e","req":"*","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"}
+                ],"cksum":"","features":{},"features2":{"extra":["dep:e"]},"yanked":false,"v":2}"#,
+                ],
+            ),
+            (
+                "1/d",
+                vec![
+                    r#"{"name":"d","vers":"1.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                ],
+            ),
+            (
+                "1/e",
+                vec![
+                    r#"{"name":"e","vers":"1.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                ],
+            ),
+            (
+                "3/w/win",
+                vec![
+                    r#"{"name":"win","vers":"1.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                ],
+            ),
+            (
+                "re/al/real-f",
+                vec![
+                    r#"{"name":"real-f","vers":"1.0.0","deps":[],"cksum":"","features":{},"yanked":false}"#,
+                ],
+            ),
+        ];
+        for (suffix, lines) in crates {
+            let path = index_path.join(suffix);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let lines: Vec<String> = lines.iter().map(|l| l.replace('\n', "")).collect();
+            fs::write(path, lines.join("\n")).unwrap();
+        }
+
+        let config = CratesConfig {
+            targets: vec!["x86_64-unknown-linux-gnu".to_owned()],
+            ..Default::default()
+        };
+        let root: RootCrate = "a@1".parse().unwrap();
+        let resolved = Resolver::new(index_path.clone(), &config)
+            .resolve(std::slice::from_ref(&root))
+            .unwrap();
+        assert_eq!(
+            resolved.keys().collect::<Vec<_>>(),
+            vec!["a", "b", "real-f"]
+        );
+        assert_eq!(
+            resolved["b"].iter().collect::<Vec<_>>(),
+            vec!["1.0.0", "1.2.0"]
+        );
+
+        // `full` enables the optional `c` with the feature enabling `e`, dev dependencies of roots
+        // are included, and all targets are included without `targets`
+        let config = CratesConfig {
+            include_dev_dependencies: true,
+            ..Default::default()
+        };
+        let root = RootCrate {
+            features: vec!["full".to_owned()],
+            ..root
+        };
+        let resolved = Resolver::new(index_path.clone(), &config)
+            .resolve(&[root])
+            .unwrap();
+        assert_eq!(
+            resolved.keys().collect::<Vec<_>>(),
+            vec!["a", "b", "c", "d", "e", "real-f", "win"]
+        );
+        fs::remove_dir_all(index_path).unwrap();
+    }
+}
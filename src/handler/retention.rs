@@ -0,0 +1,148 @@
+//! retention rules decide which versions of a crate are kept by full and incremental syncs,
+//! they are set in the crates config:
+//!
+//! ```toml
+//! skip_yanked = true
+//! # keep the latest 3 versions of each crate
+//! keep_latest = 3
+//! # and the versions published on or after the date
+//! published_after = "2024-01-01"
+//! ```
+//!
+//! A version is kept if any of `keep_latest` and `published_after` keeps it, all versions are
+//! kept if neither is set. The publish time comes from `pubtime` of the index, versions without
+//! it are treated as published before the date.
+//!
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, NaiveDate};
+use semver::Version;
+
+use crate::config::CratesConfig;
+
+use super::crates_file::IndexFile;
+
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    pub skip_yanked: bool,
+    // 0 means no limit
+    pub keep_latest: usize,
+    pub published_after: Option<NaiveDate>,
+}
+
+impl Retention {
+    pub fn new(config: &CratesConfig) -> Result<Retention, anyhow::Error> {
+        let published_after = match &config.published_after {
+            Some(date) => Some(NaiveDate::parse_from_str(date, "%Y-%m-%d")?),
+            None => None,
+        };
+        Ok(Retention {
+            skip_yanked: config.skip_yanked,
+            keep_latest: config.keep_latest,
+            published_after,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.skip_yanked && self.keep_latest == 0 && self.published_after.is_none()
+    }
+
+    /// return the kept versions from all lines of an index file
+    pub fn retain(&self, files: &[IndexFile]) -> BTreeSet<String> {
+        let candidates: Vec<&IndexFile> = files
+            .iter()
+            .filter(|file| !(self.skip_yanked && file.yanked.unwrap_or(false)))
+            .collect();
+        if self.keep_latest == 0 && self.published_after.is_none() {
+            return candidates.iter().map(|file| file.vers.clone()).collect();
+        }
+
+        let mut kept = BTreeSet::new();
+        if self.keep_latest > 0 {
+            let mut versions: Vec<(Version, &String)> = candidates
+                .iter()
+                .filter_map(|file| Some((Version::parse(&file.vers).ok()?, &file.vers)))
+                .collect();
+            versions.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+            kept.extend(
+                versions
+                    .into_iter()
+                    .take(self.keep_latest)
+                    .map(|(_, vers)| vers.clone()),
+            );
+        }
+        if let Some(date) = self.published_after {
+            kept.extend(
+                candidates
+                    .iter()
+                    .filter(|file| {
+                        file.pubtime
+                            .as_deref()
+                            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                            .is_some_and(|time| time.date_naive() >= date)
+                    })
+                    .map(|file| file.vers.clone()),
+            );
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::handler::crates_file::IndexFile;
+
+    use super::Retention;
+
+    #[test]
+    fn test_retain() {
+        let files: Vec<IndexFile> = [
+            r#"{"name":"a","vers":"0.9.0","deps":[],"features":{},"yanked":false}"#,
+            r#"{"name":"a","vers":"1.0.0","deps":[],"features":{},"yanked":false,"pubtime":"2023-06-01T00:00:00Z"}"#,
+            r#"{"name":"a","vers":"1.1.0","deps":[],"features":{},"yanked":true,"pubtime":"2024-02-01T00:00:00Z"}"#,
+            r#"{"name":"a","vers":"1.0.1","deps":[],"features":{},"yanked":false,"pubtime":"2024-01-01T08:00:00Z"}"#,
+        ]
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+        let retain = |retention: Retention| {
+            retention
+                .retain(&files)
+                .into_iter()
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(retain(Retention::default()).len(), 4);
+        let skip_yanked = Retention {
+            skip_yanked: true,
+            ..Default::default()
+        };
+        assert_eq!(retain(skip_yanked.clone()), ["0.9.0", "1.0.0", "1.0.1"]);
+        assert_eq!(
+            retain(Retention {
+                keep_latest: 2,
+                ..skip_yanked.clone()
+            }),
+            ["1.0.0", "1.0.1"]
+        );
+        let after = NaiveDate::from_ymd_opt(2024, 1, 1);
+        assert_eq!(
+            retain(Retention {
+                published_after: after,
+                ..skip_yanked.clone()
+            }),
+            ["1.0.1"]
+        );
+        assert_eq!(
+            retain(Retention {
+                keep_latest: 1,
+                published_after: after,
+                ..Default::default()
+            }),
+            ["1.0.1", "1.1.0"]
+        );
+    }
+}
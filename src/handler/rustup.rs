@@ -5,14 +5,13 @@
 //!
 //!
 
-use rayon::{ThreadPool, ThreadPoolBuilder};
-use std::{path::PathBuf, sync::Arc};
+use std::path::PathBuf;
 use url::Url;
 
 use crate::{
     config::ProxyConfig,
     config::RustUpConfig,
-    download::{download_and_check_hash, download_file_with_sha, DownloadOptions},
+    download::{download_and_check_hash, download_file_with_sha, DownloadOptions, Downloader},
     errors::FreightResult,
 };
 
@@ -48,7 +47,7 @@ const PLATFORMS: &[&str] = &[
     "x86_64-pc-windows-msvc",
 ];
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct RustUpOptions {
     pub config: RustUpConfig,
 
@@ -56,18 +55,16 @@ pub struct RustUpOptions {
 
     pub rustup_path: PathBuf,
 
-    pub thread_pool: Arc<ThreadPool>,
+    // set by `exec` once the download threads are known
+    pub downloader: Option<Downloader>,
 }
 
-impl Default for RustUpOptions {
-    fn default() -> Self {
-        let thread_pool = Arc::new(ThreadPoolBuilder::new().build().unwrap());
-        RustUpOptions {
-            thread_pool,
-            config: RustUpConfig::default(),
-            proxy: ProxyConfig::default(),
-            rustup_path: PathBuf::default(),
-        }
+impl RustUpOptions {
+    /// the downloader of rustup-init files
+    pub fn downloader(&self) -> &Downloader {
+        self.downloader
+            .as_ref()
+            .expect("the downloader is set by exec")
     }
 }
 
@@ -76,30 +73,36 @@ pub fn sync_rustup_init(opts: &RustUpOptions) -> FreightResult {
     let download_url = format!("{}/rustup/release-stable.toml", opts.config.domain);
     let file = opts.rustup_path.join("release-stable.toml");
     let down_opts = &DownloadOptions {
-        proxy: opts.proxy.clone(),
         url: Url::parse(&download_url).unwrap(),
         path: file,
     };
 
-    download_and_check_hash(down_opts, None, true).unwrap();
+    let downloader = opts.downloader();
+    downloader
+        .block_on(download_and_check_hash(downloader, down_opts, None, true))
+        .unwrap();
 
-    opts.thread_pool.scope(|s| {
-        PLATFORMS.iter().for_each(|platform| {
-            let rustup_path = opts.rustup_path.clone();
-            let file_name = if platform.contains("windows") {
-                "rustup-init.exe".to_owned()
-            } else {
-                "rustup-init".to_owned()
-            };
-            let domain = opts.config.domain.clone();
-            let proxy = opts.proxy.clone();
-            s.spawn(move |_| {
-                let download_url = format!("{}/rustup/dist/{}/{}", domain, platform, file_name);
-                let folder = rustup_path.join("dist").join(platform);
-                download_file_with_sha(&download_url, &folder, &file_name, &proxy).unwrap();
-            });
+    for platform in PLATFORMS {
+        let file_name = if platform.contains("windows") {
+            "rustup-init.exe".to_owned()
+        } else {
+            "rustup-init".to_owned()
+        };
+        let download_url = format!(
+            "{}/rustup/dist/{}/{}",
+            opts.config.domain, platform, file_name
+        );
+        let folder = opts.rustup_path.join("dist").join(platform);
+        let task_downloader = downloader.clone();
+        downloader.spawn(async move {
+            let downloaded =
+                download_file_with_sha(&task_downloader, &download_url, &folder, &file_name).await;
+            if let Err(err) = downloaded {
+                tracing::error!("failed to download {}: {:?}", download_url, err);
+            }
         });
-    });
+    }
+    downloader.wait();
 
     Ok(())
 }
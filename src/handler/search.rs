@@ -0,0 +1,292 @@
+//! search index of the local registry used by `cargo search`, it is a json file
+//! contains the latest version of every crate in the crates index and it will be
+//! rebuilt after each `crates pull`, crates published to the registry are added to it
+//! on publish.
+//!
+//!
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::errors::FreightResult;
+
+use super::crates_file::{is_not_hidden, IndexFile};
+
+// serialize all writes of the search index file
+static SEARCH_LOCK: Mutex<()> = Mutex::new(());
+
+// search indexes loaded by server keyed by file, each is reloaded when the file is modified
+#[allow(clippy::type_complexity)]
+static SEARCH_CACHE: RwLock<BTreeMap<PathBuf, (SystemTime, Arc<Vec<SearchEntry>>)>> =
+    RwLock::new(BTreeMap::new());
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchEntry {
+    pub name: String,
+    pub max_version: String,
+    pub description: Option<String>,
+}
+
+pub fn search_index_file(search_path: &Path) -> PathBuf {
+    search_path.join("search-index.json")
+}
+
+/// walk through the crates index and rebuild the search index,
+/// descriptions of published crates in the old search index are preserved
+pub fn rebuild_search_index(index_path: &Path, search_path: &Path) -> FreightResult {
+    tracing::info!("rebuilding search index from {}", index_path.display());
+    let _guard = SEARCH_LOCK.lock().unwrap();
+    let descriptions: HashMap<String, String> = read_search_index(search_path)?
+        .into_iter()
+        .filter_map(|entry| entry.description.map(|desc| (entry.name, desc)))
+        .collect();
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(index_path)
+        .into_iter()
+        .filter_entry(is_not_hidden)
+        .filter_map(|v| v.ok())
+    {
+        if !entry.file_type().is_file() || entry.path().extension().is_some() {
+            continue;
+        }
+        if let Some(mut search_entry) = latest_version(entry.path())? {
+            search_entry.description = descriptions.get(&search_entry.name).cloned();
+            entries.push(search_entry);
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    write_search_index(search_path, &entries)?;
+    tracing::info!("search index rebuilt with {} crates", entries.len());
+    Ok(())
+}
+
+/// add or replace the entry of a published crate
+pub fn upsert_search_entry(search_path: &Path, entry: SearchEntry) -> FreightResult {
+    let _guard = SEARCH_LOCK.lock().unwrap();
+    let mut entries = read_search_index(search_path)?;
+    match entries.iter_mut().find(|e| e.name == entry.name) {
+        Some(old) => {
+            let newer = match (
+                Version::parse(&old.max_version),
+                Version::parse(&entry.max_version),
+            ) {
+                (Ok(old_version), Ok(new_version)) => new_version >= old_version,
+                _ => true,
+            };
+            if newer {
+                *old = entry;
+            }
+        }
+        None => entries.push(entry),
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    write_search_index(search_path, &entries)
+}
+
+/// recompute the entry of a crate from its index file after a version is yanked or unyanked,
+/// the description of the old entry is kept
+pub fn refresh_search_entry(search_path: &Path, index_file: &Path) -> FreightResult {
+    let _guard = SEARCH_LOCK.lock().unwrap();
+    let mut entries = read_search_index(search_path)?;
+    let mut entry = match latest_version(index_file)? {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    match entries.iter_mut().find(|e| e.name == entry.name) {
+        Some(old) => {
+            entry.description = old.description.take();
+            *old = entry;
+        }
+        None => entries.push(entry),
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    write_search_index(search_path, &entries)
+}
+
+/// find crates whose name contains the query, exact match comes first,
+/// return the matched entries of the first page and the total count
+pub fn search(search_path: &Path, query: &str, per_page: usize) -> (Vec<SearchEntry>, usize) {
+    let entries = load_cached(search_path);
+    let query = normalize(query);
+    let mut matched: Vec<&SearchEntry> = entries
+        .iter()
+        .filter(|e| normalize(&e.name).contains(&query))
+        .collect();
+    matched.sort_by_key(|e| normalize(&e.name) != query);
+    let total = matched.len();
+    (matched.into_iter().take(per_page).cloned().collect(), total)
+}
+
+// crates name are case insensitive and treat '-' and '_' as the same
+fn normalize(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
+
+fn load_cached(search_path: &Path) -> Arc<Vec<SearchEntry>> {
+    let file = search_index_file(search_path);
+    let modified = match fs::metadata(&file).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return Arc::new(Vec::new()),
+    };
+    if let Some((cached_time, entries)) = SEARCH_CACHE.read().unwrap().get(&file) {
+        if *cached_time == modified {
+            return entries.clone();
+        }
+    }
+    let entries = Arc::new(read_search_index(search_path).unwrap_or_default());
+    SEARCH_CACHE
+        .write()
+        .unwrap()
+        .insert(file, (modified, entries.clone()));
+    entries
+}
+
+fn read_search_index(search_path: &Path) -> Result<Vec<SearchEntry>, anyhow::Error> {
+    match fs::read_to_string(search_index_file(search_path)) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_search_index(search_path: &Path, entries: &[SearchEntry]) -> FreightResult {
+    fs::create_dir_all(search_path)?;
+    // write to a temp file and rename it, so the server never reads a half written file
+    let file = search_index_file(search_path);
+    let tmp_file = file.with_extension("json.tmp");
+    fs::write(&tmp_file, serde_json::to_string(entries).unwrap())?;
+    fs::rename(tmp_file, file)?;
+    Ok(())
+}
+
+/// get the max version of a crate index file, yanked versions are used only if all versions are yanked
+fn latest_version(path: &Path) -> Result<Option<SearchEntry>, anyhow::Error> {
+    let buffered = BufReader::new(File::open(path)?);
+    let mut latest: Option<(bool, Version, String)> = None;
+    for line in buffered.lines() {
+        let line = line?;
+        let index_file: IndexFile = match serde_json::from_str(&line) {
+            Ok(index_file) => index_file,
+            Err(_) => continue,
+        };
+        let version = match Version::parse(&index_file.vers) {
+            Ok(version) => version,
+            Err(_) => continue,
+        };
+        let available = !index_file.yanked.unwrap_or(false);
+        let replace = match &latest {
+            Some((latest_available, latest_version, _)) => {
+                (available, &version) > (*latest_available, latest_version)
+            }
+            None => true,
+        };
+        if replace {
+            latest = Some((available, version, index_file.name));
+        }
+    }
+    Ok(latest.map(|(_, version, name)| SearchEntry {
+        name,
+        max_version: version.to_string(),
+        description: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::handler::utils::index_suffix;
+
+    use super::{
+        rebuild_search_index, refresh_search_entry, search, upsert_search_entry, SearchEntry,
+    };
+
+    #[test]
+    fn test_search_index() {
+        let work_dir = env::temp_dir().join("freighter-test-search");
+        let _ = fs::remove_dir_all(&work_dir);
+        let (index_path, search_path) = (work_dir.join("index"), work_dir.join("search"));
+        for (name, lines) in [
+            (
+                "serde",
+                vec![("1.0.1", false), ("1.0.10", false), ("1.1.0", true)],
+            ),
+            ("serde_json", vec![("1.0.0", false)]),
+        ] {
+            let path = index_path.join(index_suffix(name));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let content: String = lines
+                .iter()
+                .map(|(vers, yanked)| {
+                    format!(
+                        "{{\"name\":\"{}\",\"vers\":\"{}\",\"deps\":[],\"features\":{{}},\"yanked\":{}}}\n",
+                        name, vers, yanked
+                    )
+                })
+                .collect();
+            fs::write(path, content).unwrap();
+        }
+
+        rebuild_search_index(&index_path, &search_path).unwrap();
+        let (entries, total) = search(&search_path, "SERDE", 10);
+        assert_eq!(total, 2);
+        assert_eq!(entries[0].name, "serde");
+        assert_eq!(entries[0].max_version, "1.0.10");
+
+        // description of published crate is kept after rebuild
+        upsert_search_entry(
+            &search_path,
+            SearchEntry {
+                name: "serde_json".to_owned(),
+                max_version: "1.0.0".to_owned(),
+                description: Some("A JSON serialization file format".to_owned()),
+            },
+        )
+        .unwrap();
+        rebuild_search_index(&index_path, &search_path).unwrap();
+        let (entries, total) = search(&search_path, "serde-json", 1);
+        assert_eq!(total, 1);
+        assert!(entries[0].description.is_some());
+        assert_eq!(search(&search_path, "serde", 1).0.len(), 1);
+
+        // yanking the max version moves the entry to the previous one
+        let path = index_path.join(index_suffix("serde_json"));
+        fs::write(
+            &path,
+            "{\"name\":\"serde_json\",\"vers\":\"1.0.0\",\"yanked\":false}\n\
+             {\"name\":\"serde_json\",\"vers\":\"1.0.1\",\"yanked\":true}\n",
+        )
+        .unwrap();
+        refresh_search_entry(&search_path, &path).unwrap();
+        let (entries, _) = search(&search_path, "serde_json", 1);
+        assert_eq!(entries[0].max_version, "1.0.0");
+        assert!(entries[0].description.is_some());
+
+        // every search index is cached on its own
+        let other_path = work_dir.join("other");
+        upsert_search_entry(
+            &other_path,
+            SearchEntry {
+                name: "foo".to_owned(),
+                max_version: "0.1.0".to_owned(),
+                description: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(search(&other_path, "foo", 10).1, 1);
+        assert_eq!(search(&search_path, "serde", 10).1, 2);
+        assert_eq!(search(&other_path, "serde", 10).1, 0);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+}
@@ -22,6 +22,7 @@ fn main() {
     let result = cli::main(&mut config);
 
     if let Err(e) = result {
-        e.print()
+        e.print();
+        std::process::exit(e.code);
     }
 }
@@ -0,0 +1,329 @@
+//! pull through cache of the sparse index, index files missing under `index_path` are
+//! fetched from the upstream sparse index on request and saved with their upstream
+//! ETag under `index_path/.cache`, cached files are revalidated after `index_ttl` seconds.
+//!
+//! Index files which have no cache record, like files mirrored by `crates pull` or
+//! crates published to the registry, are never touched.
+//!
+//! Concurrent fetches of the same file are coalesced, the first request fetches it
+//! from upstream while the others wait for it and read the finished local file.
+//!
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use reqwest::{header, Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{config::Config, handler::utils};
+
+// files being fetched from upstream, the receiver is notified when the fetch is done
+static IN_FLIGHT: Mutex<Option<HashMap<PathBuf, watch::Receiver<()>>>> = Mutex::new(None);
+
+pub enum Flight {
+    // this request should fetch the file, other requests wait until the guard is dropped
+    Leader(FlightGuard),
+    // another request is fetching the file
+    Follower(watch::Receiver<()>),
+}
+
+impl Flight {
+    /// join the fetch of the file, only one request can be the leader at a time
+    pub fn join(path: &Path) -> Flight {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        let in_flight = in_flight.get_or_insert_with(HashMap::new);
+        match in_flight.get(path) {
+            Some(receiver) => Flight::Follower(receiver.clone()),
+            None => {
+                let (sender, receiver) = watch::channel(());
+                in_flight.insert(path.to_path_buf(), receiver);
+                Flight::Leader(FlightGuard {
+                    path: path.to_path_buf(),
+                    _sender: sender,
+                })
+            }
+        }
+    }
+}
+
+pub struct FlightGuard {
+    path: PathBuf,
+    // followers are woken up when the sender is dropped
+    _sender: watch::Sender<()>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        if let Some(in_flight) = IN_FLIGHT.lock().unwrap().as_mut() {
+            in_flight.remove(&self.path);
+        }
+    }
+}
+
+/// wait until the leader finishes fetching
+pub async fn wait_flight(mut receiver: watch::Receiver<()>) {
+    // returns error when the sender is dropped, which is the signal we wait for
+    while receiver.changed().await.is_ok() {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheRecord {
+    // the ETag returned by upstream, used to revalidate the cached file
+    pub etag: Option<String>,
+    // unix timestamp of the last time the file was fetched or revalidated
+    pub fetched_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexCache {
+    pub index_path: PathBuf,
+    pub upstream: String,
+    pub ttl: Duration,
+    client: Client,
+}
+
+impl IndexCache {
+    pub fn new(config: &Config) -> Self {
+        let mut client_builder = Client::builder();
+        if config.proxy.enable {
+            let proxy = reqwest::Proxy::all(config.proxy.download_proxy.clone()).unwrap();
+            client_builder = client_builder.proxy(proxy);
+        }
+        IndexCache {
+            index_path: config.index_path.to_owned(),
+            upstream: config
+                .crates
+                .sparse_index_domain
+                .trim_end_matches('/')
+                .to_owned(),
+            ttl: Duration::from_secs(config.crates.index_ttl),
+            client: client_builder.build().unwrap(),
+        }
+    }
+
+    fn record_file(&self, suffix: &str) -> PathBuf {
+        self.index_path
+            .join(".cache")
+            .join(format!("{}.json", suffix))
+    }
+
+    /// make sure the index file is available and fresh, return the local path of it,
+    /// or None if the crate doesn't exist in upstream
+    pub async fn fetch(&self, suffix: &str) -> Result<Option<PathBuf>, anyhow::Error> {
+        let name = suffix.rsplit('/').next().unwrap_or_default();
+        if !is_valid_name(name) || utils::index_suffix(&name.to_lowercase()) != suffix {
+            return Err(anyhow!("invalid index file path: {}", suffix));
+        }
+        let index_file = self.index_path.join(suffix);
+        let record = self.read_record(suffix)?;
+        let exists = index_file.exists();
+        match &record {
+            // mirrored or published index file
+            None if exists => return Ok(Some(index_file)),
+            Some(record) if exists && !self.is_stale(record) => return Ok(Some(index_file)),
+            _ => {}
+        }
+
+        let _guard = match Flight::join(&index_file) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(receiver) => {
+                wait_flight(receiver).await;
+                return Ok(index_file.exists().then_some(index_file));
+            }
+        };
+        let etag = record
+            .as_ref()
+            .filter(|_| exists)
+            .and_then(|r| r.etag.clone());
+        match self.fetch_upstream(suffix, etag).await {
+            Ok(found) => Ok(found.then_some(index_file)),
+            Err(err) if exists => {
+                // upstream is unreachable, a stale file is better than nothing
+                tracing::warn!("failed to revalidate index file {}: {:?}", suffix, err);
+                Ok(Some(index_file))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    fn is_stale(&self, record: &CacheRecord) -> bool {
+        Utc::now().timestamp() - record.fetched_at >= self.ttl.as_secs() as i64
+    }
+
+    // return false if the index file is not found in upstream
+    async fn fetch_upstream(
+        &self,
+        suffix: &str,
+        etag: Option<String>,
+    ) -> Result<bool, anyhow::Error> {
+        let url = format!("{}/{}", self.upstream, suffix);
+        tracing::info!("fetch index file from upstream: {}", url);
+        let mut request = self.client.get(&url);
+        if let Some(etag) = &etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let resp = request.send().await?;
+        match resp.status() {
+            StatusCode::NOT_MODIFIED => {
+                self.write_record(suffix, etag)?;
+                Ok(true)
+            }
+            StatusCode::NOT_FOUND
+            | StatusCode::GONE
+            | StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => {
+                // the crate is deleted from upstream, remove the stale copy
+                let _ = fs::remove_file(self.index_path.join(suffix));
+                let _ = fs::remove_file(self.record_file(suffix));
+                Ok(false)
+            }
+            status if status.is_success() => {
+                let etag = resp
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_owned());
+                let content = resp.bytes().await?;
+                write_atomic(&self.index_path.join(suffix), &content)?;
+                self.write_record(suffix, etag)?;
+                Ok(true)
+            }
+            status => Err(anyhow!("upstream returned {} for {}", status, url)),
+        }
+    }
+
+    fn read_record(&self, suffix: &str) -> Result<Option<CacheRecord>, anyhow::Error> {
+        match fs::read_to_string(self.record_file(suffix)) {
+            Ok(content) => Ok(serde_json::from_str(&content).ok()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_record(&self, suffix: &str, etag: Option<String>) -> Result<(), anyhow::Error> {
+        let record = CacheRecord {
+            etag,
+            fetched_at: Utc::now().timestamp(),
+        };
+        write_atomic(
+            &self.record_file(suffix),
+            serde_json::to_string(&record)?.as_bytes(),
+        )
+    }
+}
+
+/// crate names are made of ascii alphanumeric characters, `-` and `_`
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// write to a temp file and rename it, so the server never reads a half written file
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_file = path.with_file_name(tmp_name);
+    fs::write(&tmp_file, content)?;
+    fs::rename(tmp_file, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use warp::{http::StatusCode, Filter, Reply};
+
+    use crate::config::Config;
+
+    use super::IndexCache;
+
+    // a fake sparse index which knows only `serde`, and counts the requests
+    fn upstream(hits: Arc<AtomicUsize>) -> SocketAddr {
+        let route = warp::path!("se" / "rd" / "serde")
+            .and(warp::header::optional::<String>("If-None-Match"))
+            .map(move |etag: Option<String>| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                if etag.as_deref() == Some("\"v1\"") {
+                    return warp::reply::with_status("", StatusCode::NOT_MODIFIED).into_response();
+                }
+                warp::reply::with_header("{\"name\":\"serde\"}\n", "ETag", "\"v1\"").into_response()
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_index_cache() {
+        let work_dir = env::temp_dir().join("freighter-test-index-cache");
+        let _ = fs::remove_dir_all(&work_dir);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = upstream(hits.clone());
+
+        let mut config = Config::new();
+        config.index_path = work_dir.clone();
+        config.crates.sparse_index_domain = format!("http://{}/", addr);
+        config.crates.index_ttl = 600;
+        let cache = IndexCache::new(&config);
+
+        let path = cache.fetch("se/rd/serde").await.unwrap().unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "{\"name\":\"serde\"}\n");
+        assert_eq!(
+            cache
+                .read_record("se/rd/serde")
+                .unwrap()
+                .unwrap()
+                .etag
+                .as_deref(),
+            Some("\"v1\"")
+        );
+        // fresh file is served without asking upstream
+        cache.fetch("se/rd/serde").await.unwrap().unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // stale file is revalidated
+        let mut config = config.clone();
+        config.crates.index_ttl = 0;
+        let cache = IndexCache::new(&config);
+        assert!(cache.fetch("se/rd/serde").await.unwrap().is_some());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        assert!(cache.fetch("ra/nd/rand").await.unwrap().is_none());
+        assert!(cache.fetch("../../etc/passwd").await.is_err());
+
+        // files without cache record are never refreshed
+        fs::create_dir_all(work_dir.join("3/f")).unwrap();
+        fs::write(work_dir.join("3/f/foo"), "published").unwrap();
+        assert!(cache.fetch("3/f/foo").await.unwrap().is_some());
+        assert_eq!(
+            fs::read_to_string(work_dir.join("3/f/foo")).unwrap(),
+            "published"
+        );
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+}
@@ -8,11 +8,18 @@
 use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
+    sync::Arc,
 };
 
 use warp::{hyper::Uri, reject::Reject, Filter};
 
-use crate::config::Config;
+use crate::{
+    config::{Config, RegistryMode},
+    handler::{
+        index::{write_index_config, IndexConfig},
+        policy::Policy,
+    },
+};
 
 #[derive(Debug, PartialEq, Clone)]
 struct MissingFile {
@@ -20,6 +27,18 @@ struct MissingFile {
 }
 impl Reject for MissingFile {}
 
+// rejected when `auth_required` is enabled and the request has no valid token
+#[derive(Debug, PartialEq, Clone)]
+struct Unauthorized {
+    pub login_url: Option<String>,
+}
+impl Reject for Unauthorized {}
+
+// rejected when the tokens can't be read, it's a server error instead of a bad token
+#[derive(Debug)]
+struct TokenStoreError;
+impl Reject for TokenStoreError {}
+
 #[derive(Debug)]
 pub struct FileServer {
     pub cert_path: Option<PathBuf>,
@@ -33,7 +52,42 @@ pub struct FileServer {
 pub async fn start(config: &Config, file_server: &FileServer) {
     tracing_subscriber::fmt::init();
     // storage::init().await;
-    let routes = filters::build_route(config.to_owned())
+    // make sure both sparse and git index point cargo to this server
+    let index_config = IndexConfig::new(&config.server);
+    let mut index_paths = vec![config.index_path.to_owned(), git_index_path(config)];
+    index_paths.dedup();
+    for index_path in index_paths.iter().filter(|path| path.exists()) {
+        if let Err(err) = write_index_config(index_path, &index_config) {
+            tracing::error!(
+                "failed to write config.json to {}: {:?}",
+                index_path.display(),
+                err
+            );
+        }
+    }
+    for registry in &config.registries {
+        let registry_config = config.for_registry(registry);
+        // the index of a private registry is created here, so it can be cloned before any publish
+        if registry.mode == RegistryMode::Mirror && !registry_config.index_path.exists() {
+            continue;
+        }
+        let index_config = IndexConfig::new(&registry_config.server);
+        if let Err(err) = write_index_config(&registry_config.index_path, &index_config) {
+            tracing::error!(
+                "failed to write config.json of registry {}: {:?}",
+                registry.name,
+                err
+            );
+        }
+    }
+    let policy = match Policy::load(&config.crates.policy_path) {
+        Ok(policy) => Arc::new(policy),
+        Err(err) => {
+            tracing::error!("failed to load crates policy: {:?}", err);
+            return;
+        }
+    };
+    let routes = filters::build_route(config.to_owned(), policy)
         .recover(handlers::handle_rejection)
         .with(warp::trace::request());
 
@@ -66,86 +120,337 @@ pub async fn start(config: &Config, file_server: &FileServer) {
         }
     }
 }
+// the path of the index repository served by git protocol
+fn git_index_path(config: &Config) -> PathBuf {
+    match &config.crates.serve_index {
+        Some(path) => PathBuf::from(path).join("crates.io-index"),
+        None => config.index_path.to_owned(),
+    }
+}
+
 mod filters {
-    use std::path::PathBuf;
+    use std::{path::PathBuf, sync::Arc};
 
-    use bytes::{Buf, Bytes};
-    use warp::{Filter, Rejection};
+    use bytes::Bytes;
+    use warp::{
+        filters::BoxedFilter,
+        http::{HeaderMap, StatusCode},
+        hyper::{Body, Response},
+        Filter, Rejection, Reply,
+    };
 
     use crate::{
-        config::Config,
+        config::{Config, RegistryMode},
+        handler::{
+            index::IndexConfig,
+            policy::Policy,
+            search::{upsert_search_entry, SearchEntry},
+        },
         server::{
-            file_server::utils,
-            git_protocol::GitCommand,
-            model::{CratesPublish, Errors, PublishRsp},
+            cache::{is_valid_name, IndexCache},
+            file_server::{git_index_path, utils},
+            git_protocol::{upload_pack_backend, GitRequest, UploadPack},
+            model::{CratesPublish, OwnersReq, PublishRsp, SearchQuery},
+            owners::OwnerStore,
         },
     };
 
     use super::handlers;
 
+    // registry names which conflict with the routes of the crates.io mirror
+    const RESERVED_NAMES: &[&str] = &[
+        "api",
+        "crates",
+        "crates.io-index",
+        "dist",
+        "index",
+        "rustup",
+        "snapshots",
+    ];
+
     pub fn build_route(
         config: Config,
+        policy: Arc<Policy>,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        // GET /dist/... => ./dist/..
-        dist(config.clone())
+        // GET /{registry}/... => the named registries
+        registries(config.clone(), policy.clone())
+            // GET /dist/... => ./dist/..
+            .or(dist(config.clone()))
             .or(rustup(config.clone()))
-            .or(crates(config.clone()))
+            .or(crates(config.clone(), policy.clone()))
             .or(git(config.clone()))
-            .or(publish(config.clone()))
-            .or(sparse_index(config))
+            // the root index is the crates.io mirror
+            .or(publish(config.clone(), RegistryMode::Mirror))
+            .or(yank(config.clone()))
+            .or(unyank(config.clone()))
+            .or(owners(config.clone()))
+            .or(search(config.clone()))
+            .or(snapshots(config.clone(), policy.clone()))
+            .or(sparse_index(config, policy))
     }
 
     pub fn publish(
         config: Config,
+        mode: RegistryMode,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        // reject oversized uploads before buffering them, the body also carries the json
+        // metadata and the two length prefixes besides the crate file
+        let max_body = (config.crates.max_upload_size as u64 + 1) * 1024 * 1024;
         warp::path!("api" / "v1" / "crates" / "new")
+            .and(warp::body::content_length_limit(max_body))
             .and(warp::body::bytes())
+            .and(warp::header::optional::<String>("Authorization"))
             .and(with_config(config))
-            .map(|mut body: Bytes, config: Config| {
-                let json_len = utils::get_usize_from_bytes(body.copy_to_bytes(4));
-
-                tracing::info!("json_len: {:?}", json_len);
-                let json = body.copy_to_bytes(json_len);
+            .map(move |body: Bytes, auth: Option<String>, config: Config| {
+                let (json, file_content) = match utils::split_publish_body(body) {
+                    Ok(parts) => parts,
+                    Err(err) => return handlers::error_reply(err, StatusCode::BAD_REQUEST),
+                };
                 tracing::info!("raw json: {:?}", json);
 
                 let parse_result = serde_json::from_slice::<CratesPublish>(json.as_ref());
-                let crate_len = utils::get_usize_from_bytes(body.copy_to_bytes(4));
-                let file_content = body.copy_to_bytes(crate_len);
-
                 match parse_result {
                     Ok(result) => {
                         println!("JSON: {:?}", result);
-                        utils::save_crate_index(
+                        // the name and version are joined to the index and crates paths
+                        if let Err(err) = utils::verify_name_and_version(&result) {
+                            return handlers::error_reply(err, StatusCode::BAD_REQUEST);
+                        }
+                        let login =
+                            match handlers::authorize_owner(&config, &result.name, auth, true) {
+                                Ok(login) => login,
+                                Err(reply) => return reply,
+                            };
+                        let max_size = config.crates.max_upload_size * 1024 * 1024;
+                        if let Err(err) = utils::verify_crate_file(&result, &file_content, max_size)
+                        {
+                            return handlers::error_reply(err, StatusCode::BAD_REQUEST);
+                        }
+                        // the crate file is written before the index line, so a failed write
+                        // never leaves a published version without its file
+                        let staged = match utils::save_crate_file(
                             &result,
                             &file_content,
-                            config.index_path,
-                        );
-                        utils::save_crate_file(&result, &file_content, config.crates_path);
+                            config.crates_path,
+                        ) {
+                            Ok(staged) => staged,
+                            Err(err) => {
+                                return handlers::error_reply(
+                                    err,
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                            }
+                        };
+                        if let Err(err) = utils::save_crate_index(
+                            &result,
+                            &file_content,
+                            config.index_path.clone(),
+                            mode,
+                        ) {
+                            let _ = std::fs::remove_file(&staged);
+                            return handlers::error_reply(err, StatusCode::OK);
+                        }
+                        let crate_file =
+                            staged.with_file_name(format!("{}-{}.crate", result.name, result.vers));
+                        if let Err(err) = std::fs::rename(&staged, crate_file) {
+                            let _ = std::fs::remove_file(&staged);
+                            if let Err(err) = utils::remove_crate_index(&result, config.index_path)
+                            {
+                                tracing::error!("remove index line failed: {:?}", err);
+                            }
+                            return handlers::error_reply(err, StatusCode::INTERNAL_SERVER_ERROR);
+                        }
+                        // the first publisher of a crate becomes its owner
+                        if let Err(err) =
+                            OwnerStore::new(config.owners_path).add(&result.name, &[login])
+                        {
+                            return handlers::error_reply(err, StatusCode::INTERNAL_SERVER_ERROR);
+                        }
+                        let search_entry = SearchEntry {
+                            name: result.name.clone(),
+                            max_version: result.vers.clone(),
+                            description: result.description.clone(),
+                        };
+                        if let Err(err) = upsert_search_entry(&config.search_path, search_entry) {
+                            tracing::error!("update search index failed: {:?}", err.error);
+                        }
                         // let std::fs::write();
                         // 1.verify name and version from local db
                         // 2.call remote server to check info in crates.io
-                        warp::reply::json(&PublishRsp::default())
+                        warp::reply::with_status(
+                            warp::reply::json(&PublishRsp::default()),
+                            StatusCode::OK,
+                        )
                     }
-                    Err(err) => warp::reply::json(&Errors::new(err.to_string())),
+                    Err(err) => handlers::error_reply(err, StatusCode::OK),
                 }
             })
     }
 
+    // build '/api/v1/crates/{name}/{version}/yank' route, this route handle cargo yank request
+    pub fn yank(
+        config: Config,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("api" / "v1" / "crates" / String / String / "yank")
+            .and(warp::delete())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(with_config(config))
+            .map(
+                |name: String, version: String, auth: Option<String>, config: Config| {
+                    handlers::set_yanked(config, &name, &version, auth, true)
+                },
+            )
+    }
+
+    // build '/api/v1/crates/{name}/{version}/unyank' route, this route handle cargo yank --undo request
+    pub fn unyank(
+        config: Config,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("api" / "v1" / "crates" / String / String / "unyank")
+            .and(warp::put())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(with_config(config))
+            .map(
+                |name: String, version: String, auth: Option<String>, config: Config| {
+                    handlers::set_yanked(config, &name, &version, auth, false)
+                },
+            )
+    }
+
+    // build '/api/v1/crates/{name}/owners' route, this route handle cargo owner request
+    pub fn owners(
+        config: Config,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let list_owners = warp::path!("api" / "v1" / "crates" / String / "owners")
+            .and(warp::get())
+            .and(with_auth(config.clone()))
+            .and(with_config(config.clone()))
+            .map(|name: String, config: Config| handlers::list_owners(config, &name));
+
+        let add_owners = warp::path!("api" / "v1" / "crates" / String / "owners")
+            .and(warp::put())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::json())
+            .and(with_config(config.clone()))
+            .map(
+                |name: String, auth: Option<String>, req: OwnersReq, config: Config| {
+                    handlers::edit_owners(config, &name, auth, req, true)
+                },
+            );
+
+        let remove_owners = warp::path!("api" / "v1" / "crates" / String / "owners")
+            .and(warp::delete())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(warp::body::json())
+            .and(with_config(config))
+            .map(
+                |name: String, auth: Option<String>, req: OwnersReq, config: Config| {
+                    handlers::edit_owners(config, &name, auth, req, false)
+                },
+            );
+
+        list_owners.or(add_owners).or(remove_owners)
+    }
+
+    // build '/api/v1/crates?q={query}&per_page={per_page}' route, this route handle cargo search request
+    pub fn search(
+        config: Config,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("api" / "v1" / "crates")
+            .and(warp::get())
+            .and(with_auth(config.clone()))
+            .and(warp::query::<SearchQuery>())
+            .and(with_config(config))
+            .map(|query: SearchQuery, config: Config| handlers::search(config, query))
+    }
+
     pub fn sparse_index(
         config: Config,
+        policy: Arc<Policy>,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path("index")
+        // config.json is generated from config instead of the one mirrored from crates.io
+        let index_config = warp::path!("index" / "config.json")
+            .and(with_auth(config.clone()))
+            .and(with_config(config.clone()))
+            .map(|config: Config| warp::reply::json(&IndexConfig::new(&config.server)));
+
+        let cache = IndexCache::new(&config);
+        let index_files = warp::path("index")
+            .and(with_auth(config.clone()))
             .and(warp::path::tail())
+            .and(warp::header::headers_cloned())
+            .and(warp::any().map(move || cache.clone()))
+            .and(with_policy(policy))
             .and(with_config(config))
-            .and_then(|tail: warp::path::Tail, config: Config| async move {
-                handlers::return_files(
-                    config.rustup.serve_domains.unwrap(),
-                    config.index_path,
-                    PathBuf::from("crates.io-index").join(tail.as_str()),
-                    false,
-                )
-                .await
-            })
+            .and_then(
+                |tail: warp::path::Tail,
+                 headers: HeaderMap,
+                 cache: IndexCache,
+                 policy: Arc<Policy>,
+                 config: Config| async move {
+                    if policy.is_empty() {
+                        return handlers::return_index(config, cache, tail.as_str(), headers).await;
+                    }
+                    let inner_headers = utils::without_conditionals(&headers);
+                    let resp =
+                        handlers::return_index(config, cache, tail.as_str(), inner_headers).await?;
+                    handlers::apply_policy(resp, &policy, &headers).await
+                },
+            );
+
+        index_config.or(index_files)
+    }
+
+    // build '/snapshots' route, which serves the sparse index at a synced commit
+    pub fn snapshots(
+        config: Config,
+        policy: Arc<Policy>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let list = warp::path!("snapshots")
+            .and(warp::get())
+            .and(with_auth(config.clone()))
+            .and(with_config(config.clone()))
+            .map(|config: Config| handlers::list_snapshots(&config));
+
+        let index_config = warp::path!("snapshots" / String / "index" / "config.json")
+            .and(with_auth(config.clone()))
+            .and(with_config(config.clone()))
+            .and_then(|id: String, config: Config| async move {
+                handlers::snapshot_index_config(&config, &id)
+            });
+
+        let index_files = warp::path("snapshots")
+            .and(warp::path::param::<String>())
+            .and(warp::path("index"))
+            .and(with_auth(config.clone()))
+            .and(warp::path::tail())
+            .and(warp::header::headers_cloned())
+            .and(with_policy(policy))
+            .and(with_config(config))
+            .and_then(
+                |id: String,
+                 tail: warp::path::Tail,
+                 headers: HeaderMap,
+                 policy: Arc<Policy>,
+                 config: Config| async move {
+                    if policy.is_empty() {
+                        return handlers::return_snapshot_index(
+                            config,
+                            &id,
+                            tail.as_str(),
+                            headers,
+                        )
+                        .await;
+                    }
+                    let inner_headers = utils::without_conditionals(&headers);
+                    let resp =
+                        handlers::return_snapshot_index(config, &id, tail.as_str(), inner_headers)
+                            .await?;
+                    handlers::apply_policy(resp, &policy, &headers).await
+                },
+            );
+
+        list.or(index_config).or(index_files)
     }
 
     // build '/dist/*' route, this route handle rust toolchian files request
@@ -153,17 +458,22 @@ mod filters {
         config: Config,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("dist")
+            .and(with_auth(config.clone()))
             .and(warp::path::tail())
+            .and(warp::header::headers_cloned())
             .and(with_config(config))
-            .and_then(|tail: warp::path::Tail, config: Config| async move {
-                handlers::return_files(
-                    config.rustup.serve_domains.unwrap(),
-                    config.dist_path,
-                    PathBuf::from("dist").join(tail.as_str()),
-                    false,
-                )
-                .await
-            })
+            .and_then(
+                |tail: warp::path::Tail, headers: HeaderMap, config: Config| async move {
+                    handlers::return_files(
+                        config.rustup.serve_domains.unwrap(),
+                        config.dist_path,
+                        PathBuf::from("dist").join(tail.as_str()),
+                        false,
+                        headers,
+                    )
+                    .await
+                },
+            )
             .recover(handlers::handle_missing_file)
     }
 
@@ -173,92 +483,215 @@ mod filters {
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path("rustup")
             .and(warp::path::tail())
+            .and(warp::header::headers_cloned())
             .and(with_config(config))
-            .and_then(move |tail: warp::path::Tail, config: Config| async move {
-                handlers::return_files(
-                    config.rustup.serve_domains.unwrap(),
-                    config.rustup_path,
-                    PathBuf::from("rustup").join(tail.as_str()),
-                    false,
-                )
-                .await
-            })
+            .and_then(
+                move |tail: warp::path::Tail, headers: HeaderMap, config: Config| async move {
+                    handlers::return_files(
+                        config.rustup.serve_domains.unwrap(),
+                        config.rustup_path,
+                        PathBuf::from("rustup").join(tail.as_str()),
+                        false,
+                        headers,
+                    )
+                    .await
+                },
+            )
             .recover(handlers::handle_missing_file)
     }
 
     // build '/crates/*' route, this route handle crates file request
     pub fn crates(
         config: Config,
+        policy: Arc<Policy>,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         let crates_1 = warp::path!("crates" / String / String / "download")
             .map(|name: String, version: String| (name, version))
             .untuple_one();
         let crates_2 = warp::path!("crates" / String / String)
             .map(|name: String, file: String| {
-                let split: Vec<_> = file.split('-').collect();
-                let version = split[split.len() - 1].replace(".crate", "");
+                // the version may contain `-`, like `{name}-1.0.0-beta.1.crate`
+                let version = match file.strip_prefix(&format!("{}-", name)) {
+                    Some(rest) => rest.trim_end_matches(".crate").to_owned(),
+                    None => {
+                        let split: Vec<_> = file.split('-').collect();
+                        split[split.len() - 1].replace(".crate", "")
+                    }
+                };
                 (name, version)
             })
             .untuple_one();
 
+        let cache = IndexCache::new(&config);
         crates_1
             .or(crates_2)
             .unify()
+            .and(with_auth(config.clone()))
+            .and(warp::header::headers_cloned())
+            .and(warp::any().map(move || cache.clone()))
+            .and(with_policy(policy))
             .and(with_config(config))
-            .and_then(|name: String, version: String, config: Config| async move {
-                let file_path = PathBuf::from("crates")
-                    .join(&name)
-                    .join(format!("{}-{}.crate", name, version));
-                handlers::return_files(
-                    config.crates.serve_domains.unwrap(),
-                    config.crates_path,
-                    file_path,
-                    true,
-                )
-                .await
-            })
+            .and_then(
+                |name: String,
+                 version: String,
+                 headers: HeaderMap,
+                 cache: IndexCache,
+                 policy: Arc<Policy>,
+                 config: Config| async move {
+                    if let Err(reason) = policy.check(&name, &version) {
+                        return Ok(
+                            handlers::error_reply(reason, StatusCode::FORBIDDEN).into_response()
+                        );
+                    }
+                    if config.crates.pull_through {
+                        return handlers::return_cached_crate(
+                            config, cache, &name, &version, headers,
+                        )
+                        .await;
+                    }
+                    let file_path = PathBuf::from("crates")
+                        .join(&name)
+                        .join(format!("{}-{}.crate", name, version));
+                    handlers::return_files(
+                        config.crates.serve_domains.unwrap(),
+                        config.crates_path,
+                        file_path,
+                        true,
+                        headers,
+                    )
+                    .await
+                    .map(|reply| reply.into_response())
+                },
+            )
             .recover(handlers::handle_missing_file)
     }
 
     // build '/crate.io-index/(git protocol)' route, this route handle gti clone and git pull request
     pub fn git(
-        // git_work_dir: PathBuf,
         config: Config,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        let git_work_dir = if let Some(path) = &config.crates.serve_index {
-            PathBuf::from(path)
-        } else {
-            config.index_path
-        };
-        
+        warp::path("crates.io-index").and(git_service(config))
+    }
+
+    // the git smart http services of the index repository, under the url path of the repository
+    fn git_service(
+        config: Config,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let repo_path = git_index_path(&config);
+        let backend = upload_pack_backend(config.crates.git_backend);
+        let with_backend = warp::any().map(move || backend.clone());
+
         let git_upload_pack = warp::path!("git-upload-pack")
-            .and(warp::path::tail())
-            .and(warp::method())
-            .and(warp::body::aggregate())
-            .and(warp::header::optional::<String>("Content-Type"))
+            .and(warp::body::bytes())
+            .and(with_repo_path(repo_path.to_owned()))
+            .and(git_request())
+            .and(with_backend.clone())
+            .then(
+                |body, repo_path, request, backend: Arc<dyn UploadPack>| async move {
+                    handlers::git_reply(backend.upload_pack(body, repo_path, request).await)
+                },
+            );
+
+        let git_info_refs = warp::path!("info" / "refs")
+            .and(warp::body::bytes())
+            .and(with_repo_path(repo_path))
+            .and(git_request())
+            .and(with_backend)
+            .then(
+                |body, repo_path, request, backend: Arc<dyn UploadPack>| async move {
+                    handlers::git_reply(backend.info_refs(body, repo_path, request).await)
+                },
+            );
+
+        with_auth(config).and(git_upload_pack.or(git_info_refs))
+    }
+
+    // build '/{registry}/*' routes of the named registries, the git and sparse index of a
+    // registry are both under '/{registry}/index'
+    pub fn registries(config: Config, policy: Arc<Policy>) -> BoxedFilter<(Response<Body>,)> {
+        let mut route = warp::any()
+            .and_then(|| async { Err::<Response<Body>, Rejection>(warp::reject::not_found()) })
+            .boxed();
+        for registry in &config.registries {
+            if !is_valid_name(&registry.name) || RESERVED_NAMES.contains(&registry.name.as_str()) {
+                tracing::error!("invalid registry name: {}", registry.name);
+                continue;
+            }
+            // the policy is written for crates from crates.io, crates published to a private
+            // registry are never filtered by it
+            let policy = match registry.mode {
+                RegistryMode::Mirror => policy.clone(),
+                RegistryMode::Private => Arc::default(),
+            };
+            let registry_route = warp::path(registry.name.clone()).and(registry_api(
+                config.for_registry(registry),
+                registry.mode,
+                policy,
+            ));
+            route = route.or(registry_route).unify().boxed();
+        }
+        route
+    }
+
+    fn registry_api(
+        config: Config,
+        mode: RegistryMode,
+        policy: Arc<Policy>,
+    ) -> BoxedFilter<(Response<Body>,)> {
+        let read = warp::path("index")
+            .and(git_service(config.clone()))
+            .or(sparse_index(config.clone(), policy.clone()))
+            .or(crates(config.clone(), policy))
+            .or(search(config.clone()))
+            .map(Reply::into_response);
+        match mode {
+            RegistryMode::Mirror => read.boxed(),
+            RegistryMode::Private => read
+                .or(publish(config.clone(), mode))
+                .or(yank(config.clone()))
+                .or(unyank(config.clone()))
+                .or(owners(config))
+                .map(Reply::into_response)
+                .boxed(),
+        }
+    }
+
+    // collect the method, query and headers that git http-backend negotiates with
+    fn git_request() -> impl Filter<Extract = (GitRequest,), Error = Rejection> + Clone {
+        warp::method()
             .and(
                 warp::query::raw()
                     .or_else(|_| async { Ok::<(String,), Rejection>((String::new(),)) }),
             )
-            .and(with_work_dir(git_work_dir.to_owned()))
-            .and_then(
-                |_tail, method, body, content_type, _query, work_dir| async move {
-                    let git_protocol = GitCommand::default();
-                    git_protocol
-                        .git_upload_pack(body, work_dir, method, content_type)
-                        .await
+            .and(warp::header::optional::<String>("Content-Type"))
+            .and(warp::header::optional::<String>("Content-Encoding"))
+            .and(warp::header::optional::<String>("Git-Protocol"))
+            .map(
+                |method, query, content_type, content_encoding, git_protocol| GitRequest {
+                    method,
+                    query,
+                    content_type,
+                    content_encoding,
+                    git_protocol,
                 },
-            );
+            )
+    }
 
-        let git_info_refs = warp::path!("info" / "refs")
-            .and(warp::body::aggregate())
-            .and(with_work_dir(git_work_dir))
-            .and_then(|body, work_dir| async move {
-                let git_protocol = GitCommand::default();
-                git_protocol.git_info_refs(body, work_dir).await
-            });
+    // reject the request without a valid token if `auth_required` is enabled
+    fn with_auth(config: Config) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>("Authorization")
+            .and(with_config(config))
+            .and_then(|auth: Option<String>, config: Config| async move {
+                handlers::require_token(&config, auth)
+            })
+            .untuple_one()
+    }
 
-        warp::path("crates.io-index").and(git_upload_pack.or(git_info_refs))
+    // the policy is loaded once when the server starts
+    fn with_policy(
+        policy: Arc<Policy>,
+    ) -> impl Filter<Extract = (Arc<Policy>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || policy.clone())
     }
 
     fn with_config(
@@ -267,62 +700,393 @@ mod filters {
         warp::any().map(move || config.clone())
     }
 
-    fn with_work_dir(
-        work_dir: PathBuf,
+    fn with_repo_path(
+        repo_path: PathBuf,
     ) -> impl Filter<Extract = (PathBuf,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || work_dir.clone())
+        warp::any().map(move || repo_path.clone())
     }
 }
 
 mod handlers {
-    use std::{borrow::BorrowMut, convert::Infallible, error::Error, path::PathBuf, str::FromStr};
+    use std::{convert::Infallible, error::Error, io::SeekFrom, path::PathBuf, str::FromStr};
 
-    use reqwest::Url;
+    use anyhow::anyhow;
+    use chrono::{DateTime, Utc};
+    use reqwest::{Client, Url};
     use serde::Serialize;
-    use tokio::{fs::File, io::AsyncWriteExt};
+    use sha2::{Digest, Sha256};
+    use tokio::{
+        fs::File,
+        io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    };
     use tokio_util::codec::{BytesCodec, FramedRead};
     use url::form_urlencoded::byte_serialize;
     use warp::{
         http,
-        http::StatusCode,
+        http::{HeaderMap, StatusCode},
         hyper::{Body, Response, Uri},
-        reject, Rejection, Reply,
+        reject,
+        reply::{Json, WithStatus},
+        Rejection, Reply,
     };
 
     use crate::{
+        config::Config,
         download,
-        errors::{FreightResult, FreighterError},
-        server::file_server::MissingFile,
+        handler::{
+            index::{self, IndexBlob, IndexConfig, Snapshot},
+            policy::Policy,
+            search,
+            utils::index_suffix,
+        },
+        server::{
+            cache::{is_valid_name, wait_flight, Flight, FlightGuard, IndexCache},
+            file_server::{utils, MissingFile, TokenStoreError, Unauthorized},
+            git_protocol::GitError,
+            model::{
+                Errors, OwnersEditRsp, OwnersReq, OwnersRsp, SearchMeta, SearchQuery, SearchRsp,
+                SnapshotsRsp, User, YankRsp,
+            },
+            owners::OwnerStore,
+            tokens::TokenStore,
+        },
     };
 
-    async fn download_local_files(full_path: &PathBuf) -> Result<Response<Body>, Rejection> {
-        let file = File::open(full_path)
+    async fn download_local_files(
+        full_path: &PathBuf,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        let mut file = File::open(full_path)
             .await
             .map_err(|_| reject::not_found())?;
 
         let meta = file.metadata().await.map_err(|_| reject::not_found())?;
-        let stream = FramedRead::new(file, BytesCodec::new());
+        if !meta.is_file() {
+            return Err(reject::not_found());
+        }
+        // validators let cargo and rustup skip unchanged files
+        let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+        let etag = utils::file_etag(meta.len(), modified);
+        let last_modified = modified.map(utils::http_date);
 
-        let body = Body::wrap_stream(stream);
+        // a single range lets interrupted downloads resume, the range is ignored if
+        // the file has changed since the client got the first part
+        let range = headers
+            .get(http::header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| utils::is_range_fresh(headers, &etag, last_modified.as_deref()))
+            .and_then(|range| utils::parse_range(range, meta.len()));
 
-        let mut resp = Response::new(body);
+        let mut resp = if utils::is_not_modified(headers, &etag, modified) {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_MODIFIED;
+            resp
+        } else {
+            match range {
+                Some(Ok((start, end))) => {
+                    file.seek(SeekFrom::Start(start))
+                        .await
+                        .map_err(|_| reject::not_found())?;
+                    let stream = FramedRead::new(file.take(end - start + 1), BytesCodec::new());
+                    let mut resp = Response::new(Body::wrap_stream(stream));
+                    *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+                    let headers = resp.headers_mut();
+                    headers.insert(http::header::CONTENT_LENGTH, (end - start + 1).into());
+                    headers.insert(
+                        http::header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, meta.len())
+                            .parse()
+                            .unwrap(),
+                    );
+                    resp
+                }
+                Some(Err(())) => {
+                    let mut resp = Response::new(Body::empty());
+                    *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                    resp.headers_mut().insert(
+                        http::header::CONTENT_RANGE,
+                        format!("bytes */{}", meta.len()).parse().unwrap(),
+                    );
+                    resp
+                }
+                None => {
+                    let stream = FramedRead::new(file, BytesCodec::new());
+                    let mut resp = Response::new(Body::wrap_stream(stream));
+                    resp.headers_mut()
+                        .insert(http::header::CONTENT_LENGTH, meta.len().into());
+                    resp
+                }
+            }
+        };
+        resp.headers_mut().insert(
+            http::header::ACCEPT_RANGES,
+            http::HeaderValue::from_static("bytes"),
+        );
         resp.headers_mut()
-            .insert(http::header::CONTENT_LENGTH, meta.len().into());
+            .insert(http::header::ETAG, etag.parse().unwrap());
+        if let Some(last_modified) = last_modified {
+            resp.headers_mut()
+                .insert(http::header::LAST_MODIFIED, last_modified.parse().unwrap());
+        }
+
+        Ok(resp)
+    }
+
+    pub fn list_snapshots(config: &Config) -> WithStatus<Json> {
+        match index::read_snapshots(&config.log_path) {
+            Ok(snapshots) => warp::reply::with_status(
+                warp::reply::json(&SnapshotsRsp { snapshots }),
+                StatusCode::OK,
+            ),
+            Err(err) => error_reply(err, StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    fn find_snapshot(config: &Config, id: &str) -> Result<Snapshot, Rejection> {
+        let snapshots = index::read_snapshots(&config.log_path).map_err(|err| {
+            tracing::error!("failed to read snapshots: {:?}", err);
+            reject::not_found()
+        })?;
+        index::find_snapshot(&snapshots, id)
+            .cloned()
+            .ok_or_else(reject::not_found)
+    }
+
+    /// the config.json of a snapshot is the same as the current index
+    pub fn snapshot_index_config(config: &Config, id: &str) -> Result<Json, Rejection> {
+        find_snapshot(config, id)?;
+        Ok(warp::reply::json(&IndexConfig::new(&config.server)))
+    }
+
+    /// serve index file from the git objects of a snapshot
+    pub async fn return_snapshot_index(
+        config: Config,
+        id: &str,
+        suffix: &str,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        let snapshot = find_snapshot(&config, id)?;
+        let path = PathBuf::from(suffix);
+        let blob = tokio::task::spawn_blocking(move || {
+            index::read_index_blob(&config.index_path, &snapshot.commit, &path)
+        })
+        .await
+        .map_err(|_| reject::not_found())?;
+        match blob {
+            Ok(Some(blob)) => Ok(blob_reply(blob, &headers)),
+            Ok(None) => Err(reject::not_found()),
+            Err(err) => {
+                tracing::error!("failed to read index file {}: {:?}", suffix, err);
+                Ok(error_reply(err, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+            }
+        }
+    }
+
+    /// serve index file from the git objects, the pull through cache or the working tree
+    pub async fn return_index(
+        config: Config,
+        cache: IndexCache,
+        suffix: &str,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        if config.crates.serve_from_git {
+            return return_git_index(config, suffix, headers).await;
+        }
+        if config.crates.pull_through {
+            return return_cached_index(cache, suffix, headers).await;
+        }
+        let index_dir = config.index_path.file_name().unwrap_or_default();
+        return_files(
+            config.crates.serve_domains.unwrap_or_default(),
+            config.index_path.parent().unwrap().to_path_buf(),
+            PathBuf::from(index_dir).join(suffix),
+            false,
+            headers,
+        )
+        .await
+        .map(|reply| reply.into_response())
+    }
 
+    /// remove denied versions from the index file, the ETag is made from the filtered
+    /// content so clients see the change when the policy is changed
+    pub async fn apply_policy(
+        resp: Response<Body>,
+        policy: &Policy,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        if resp.status() != StatusCode::OK {
+            return Ok(resp);
+        }
+        let content = warp::hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|_| reject::not_found())?;
+        let content = utils::filter_index_lines(&content, policy);
+        if content.is_empty() {
+            return Err(reject::not_found());
+        }
+        let etag = format!("\"{:x}\"", Sha256::digest(&content));
+        let mut resp = if utils::is_not_modified(headers, &etag, None) {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_MODIFIED;
+            resp
+        } else {
+            let len = content.len();
+            let mut resp = Response::new(Body::from(content));
+            resp.headers_mut()
+                .insert(http::header::CONTENT_LENGTH, len.into());
+            resp
+        };
+        resp.headers_mut()
+            .insert(http::header::ETAG, etag.parse().unwrap());
         Ok(resp)
     }
 
+    /// serve index file from the git objects of the served commit
+    pub async fn return_git_index(
+        config: Config,
+        suffix: &str,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        let path = PathBuf::from(suffix);
+        let blob =
+            tokio::task::spawn_blocking(move || index::read_served_blob(&config.index_path, &path))
+                .await
+                .map_err(|_| reject::not_found())?;
+        match blob {
+            Ok(Some(blob)) => Ok(blob_reply(blob, &headers)),
+            Ok(None) => Err(reject::not_found()),
+            Err(err) => {
+                tracing::error!("failed to read index file {}: {:?}", suffix, err);
+                Ok(error_reply(err, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+            }
+        }
+    }
+
+    // the blob id is a perfect validator of the file content
+    fn blob_reply(blob: IndexBlob, headers: &HeaderMap) -> Response<Body> {
+        let etag = format!("\"{}\"", blob.oid);
+        let modified = DateTime::<Utc>::from_timestamp(blob.time, 0);
+        let mut resp = if utils::is_not_modified(headers, &etag, modified) {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_MODIFIED;
+            resp
+        } else {
+            let len = blob.content.len();
+            let mut resp = Response::new(Body::from(blob.content));
+            resp.headers_mut()
+                .insert(http::header::CONTENT_LENGTH, len.into());
+            resp
+        };
+        resp.headers_mut()
+            .insert(http::header::ETAG, etag.parse().unwrap());
+        if let Some(modified) = modified {
+            resp.headers_mut().insert(
+                http::header::LAST_MODIFIED,
+                utils::http_date(modified).parse().unwrap(),
+            );
+        }
+        resp
+    }
+
+    /// serve index file from the pull through cache
+    pub async fn return_cached_index(
+        cache: IndexCache,
+        suffix: &str,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        // a path that can't be an index file is not found, there is nothing to fetch
+        let name = suffix.rsplit('/').next().unwrap_or_default();
+        if !is_valid_name(name) || index_suffix(&name.to_lowercase()) != suffix {
+            return Err(reject::not_found());
+        }
+        match cache.fetch(suffix).await {
+            Ok(Some(path)) => download_local_files(&path, &headers).await,
+            Ok(None) => Err(reject::not_found()),
+            Err(err) => {
+                tracing::error!("failed to fetch index file {}: {:?}", suffix, err);
+                Ok(error_reply(err, StatusCode::BAD_GATEWAY).into_response())
+            }
+        }
+    }
+
+    /// serve crate file from local, it's fetched from upstream and saved under
+    /// `crates_path` if missing, and the checksum is verified against the index
+    pub async fn return_cached_crate(
+        config: Config,
+        cache: IndexCache,
+        name: &str,
+        version: &str,
+        headers: HeaderMap,
+    ) -> Result<Response<Body>, Rejection> {
+        if !is_valid_name(name) {
+            return Err(reject::not_found());
+        }
+        let file_name = format!("{}-{}.crate", name, version);
+        let full_path = config.crates_path.join(name).join(&file_name);
+        if full_path.exists() {
+            return download_local_files(&full_path, &headers).await;
+        }
+
+        let cksum = match cache.fetch(&index_suffix(&name.to_lowercase())).await {
+            Ok(Some(index_file)) => utils::find_cksum(&index_file, version),
+            Ok(None) => return Err(reject::not_found()),
+            Err(err) => return Ok(error_reply(err, StatusCode::BAD_GATEWAY).into_response()),
+        };
+        let cksum = match cksum {
+            Ok(Some(cksum)) => cksum,
+            Ok(None) => return Err(reject::not_found()),
+            Err(err) => {
+                return Ok(error_reply(err, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+            }
+        };
+
+        // concurrent requests of the same crate wait for the first one
+        let guard = match Flight::join(&full_path) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(receiver) => {
+                wait_flight(receiver).await;
+                if full_path.exists() {
+                    return download_local_files(&full_path, &headers).await;
+                }
+                return Ok(error_reply(
+                    format!("failed to fetch crate `{}` from upstream", file_name),
+                    StatusCode::BAD_GATEWAY,
+                )
+                .into_response());
+            }
+        };
+        // the leader may start after the previous fetch has finished
+        if full_path.exists() {
+            return download_local_files(&full_path, &headers).await;
+        }
+
+        let url = format!("{}/{}/{}", config.crates.domain, name, file_name);
+        match download_from_remote(cache.client(), full_path, &url, cksum, guard).await {
+            Ok(resp) => Ok(resp),
+            Err(err) => {
+                tracing::error!("failed to fetch crate from {}: {:?}", url, err);
+                Ok(error_reply(err, StatusCode::BAD_GATEWAY).into_response())
+            }
+        }
+    }
+
     pub async fn return_files(
         serve_domains: Vec<String>,
         work_dir: PathBuf,
         mut file_path: PathBuf,
         is_crates: bool,
+        headers: HeaderMap,
     ) -> Result<impl Reply, Rejection> {
         for domain in serve_domains {
             if domain.eq("localhost") {
-                let full_path = work_dir.join(file_path.clone());
+                // crates are saved under crates_path without the `crates` prefix of the url
+                let local_path = match file_path.strip_prefix("crates") {
+                    Ok(path) if is_crates => path,
+                    _ => &file_path,
+                };
+                let full_path = work_dir.join(local_path);
                 tracing::info!("try to fetch file from local: {}", full_path.display());
-                let res = download_local_files(&full_path).await;
+                let res = download_local_files(&full_path, &headers).await;
                 if res.is_ok() {
                     return res;
                 }
@@ -350,44 +1114,231 @@ mod handlers {
         Err(reject::not_found())
     }
 
-    /// An API error serializable to JSON.
-    #[derive(Serialize)]
-    struct ErrorMessage {
-        code: u16,
-        message: String,
+    /// build a cargo compatible error response
+    pub fn error_reply(detail: impl ToString, status: StatusCode) -> WithStatus<Json> {
+        warp::reply::with_status(warp::reply::json(&Errors::new(detail.to_string())), status)
     }
 
-    /// ### References Codes
-    ///
-    /// - [warp](<https://github.com/seanmonstar/warp>)'s rejections (example)[<https://github.com/seanmonstar/warp/blob/master/examples/rejections.rs>].
-    ///
-    ///
-    // This function receives a `Rejection` and tries to return a custom
-    // value, otherwise simply passes the rejection along.
-    pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-        let code;
-        let message;
-        if err.is_not_found() {
-            code = StatusCode::NOT_FOUND;
-            message = "NOT_FOUND";
-        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
-            // This error happens if the body could not be deserialized correctly
-            // We can use the cause to analyze the error and customize the error message
-            message = match e.source() {
-                Some(cause) => {
-                    if cause.to_string().contains("denom") {
-                        "FIELD_ERROR: denom"
-                    } else {
-                        "BAD_REQUEST"
-                    }
-                }
-                None => "BAD_REQUEST",
-            };
-            code = StatusCode::BAD_REQUEST;
-        } else if err.find::<reject::MethodNotAllowed>().is_some() {
-            // We can handle a specific error, here METHOD_NOT_ALLOWED,
-            // and render it however we want
-            code = StatusCode::METHOD_NOT_ALLOWED;
+    /// turn the result of git requests into response, errors are logged and returned with the status
+    pub fn git_reply(result: Result<Response<Body>, GitError>) -> Response<Body> {
+        match result {
+            Ok(resp) => resp,
+            Err(err) => {
+                tracing::error!("git request failed: {:?}", err.error);
+                error_reply(err.error, err.status).into_response()
+            }
+        }
+    }
+
+    /// check the token when `auth_required` is enabled, the rejection is turned into
+    /// 401 with a `WWW-Authenticate` header so cargo will send the token
+    pub fn require_token(config: &Config, auth: Option<String>) -> Result<(), Rejection> {
+        if !config.server.auth_required {
+            return Ok(());
+        }
+        let login = match utils::token_from_header(auth) {
+            Some(token) => TokenStore::new(config.tokens_path.clone()).login(&token),
+            None => Ok(None),
+        };
+        match login {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(reject::custom(Unauthorized {
+                login_url: config.server.login_url(),
+            })),
+            Err(err) => {
+                tracing::error!("failed to read tokens: {:?}", err);
+                Err(reject::custom(TokenStoreError))
+            }
+        }
+    }
+
+    /// find the login of the token which cargo sends in the Authorization header
+    pub fn authenticate(config: &Config, auth: Option<String>) -> Result<String, WithStatus<Json>> {
+        let token = match utils::token_from_header(auth) {
+            Some(token) => token,
+            _ => {
+                return Err(error_reply(
+                    "this operation requires a token, please run `cargo login` first",
+                    StatusCode::FORBIDDEN,
+                ))
+            }
+        };
+        match TokenStore::new(config.tokens_path.clone()).login(&token) {
+            Ok(Some(login)) => Ok(login),
+            Ok(None) => Err(error_reply(
+                "the token is invalid or has been revoked",
+                StatusCode::FORBIDDEN,
+            )),
+            Err(err) => Err(error_reply(err, StatusCode::INTERNAL_SERVER_ERROR)),
+        }
+    }
+
+    /// check the caller is an owner of the crate and return the caller's login,
+    /// a crate that doesn't exist in local index can be claimed by anyone if `allow_new` is set
+    pub fn authorize_owner(
+        config: &Config,
+        name: &str,
+        auth: Option<String>,
+        allow_new: bool,
+    ) -> Result<String, WithStatus<Json>> {
+        let login = authenticate(config, auth)?;
+        let owners = OwnerStore::new(config.owners_path.clone())
+            .list(name)
+            .map_err(|err| error_reply(err, StatusCode::INTERNAL_SERVER_ERROR))?;
+        let is_new = owners.is_empty() && !utils::is_name_taken(&config.index_path, name);
+        if owners.contains(&login) || (allow_new && is_new) {
+            Ok(login)
+        } else {
+            Err(error_reply(
+                format!("user `{}` is not an owner of crate `{}`", login, name),
+                StatusCode::FORBIDDEN,
+            ))
+        }
+    }
+
+    /// flip the yanked flag of a crate version in the local index and build the cargo response
+    pub fn set_yanked(
+        config: Config,
+        name: &str,
+        version: &str,
+        auth: Option<String>,
+        yanked: bool,
+    ) -> WithStatus<Json> {
+        if let Err(reply) = authorize_owner(&config, name, auth, false) {
+            return reply;
+        }
+        match utils::set_crate_yanked(config.index_path.clone(), name, version, yanked) {
+            Ok(()) => {
+                let index_file = config.index_path.join(index_suffix(&name.to_lowercase()));
+                if let Err(err) = search::refresh_search_entry(&config.search_path, &index_file) {
+                    tracing::error!("update search index failed: {:?}", err.error);
+                }
+                warp::reply::with_status(warp::reply::json(&YankRsp::default()), StatusCode::OK)
+            }
+            Err(err) => error_reply(err, StatusCode::OK),
+        }
+    }
+
+    pub fn list_owners(config: Config, name: &str) -> WithStatus<Json> {
+        match OwnerStore::new(config.owners_path).list(name) {
+            Ok(owners) => {
+                let users = owners
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, login)| User {
+                        id: id as u32 + 1,
+                        login,
+                        name: None,
+                    })
+                    .collect();
+                warp::reply::with_status(warp::reply::json(&OwnersRsp { users }), StatusCode::OK)
+            }
+            Err(err) => error_reply(err, StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    /// search crates in local registry, per_page is limited to 100 like crates.io
+    pub fn search(config: Config, query: SearchQuery) -> WithStatus<Json> {
+        let per_page = query.per_page.unwrap_or(10).min(100);
+        let (crates, total) = search::search(&config.search_path, &query.q, per_page);
+        let rsp = SearchRsp {
+            crates,
+            meta: SearchMeta { total },
+        };
+        warp::reply::with_status(warp::reply::json(&rsp), StatusCode::OK)
+    }
+
+    /// add or remove owners of a crate, only owners can edit the owner list
+    pub fn edit_owners(
+        config: Config,
+        name: &str,
+        auth: Option<String>,
+        req: OwnersReq,
+        add: bool,
+    ) -> WithStatus<Json> {
+        if let Err(reply) = authorize_owner(&config, name, auth, false) {
+            return reply;
+        }
+        let store = OwnerStore::new(config.owners_path);
+        let (res, action) = if add {
+            (store.add(name, &req.users), "added to")
+        } else {
+            (store.remove(name, &req.users), "removed from")
+        };
+        match res {
+            Ok(()) => {
+                let rsp = OwnersEditRsp {
+                    ok: true,
+                    msg: format!(
+                        "{} has been {} crate `{}`",
+                        req.users.join(", "),
+                        action,
+                        name
+                    ),
+                };
+                warp::reply::with_status(warp::reply::json(&rsp), StatusCode::OK)
+            }
+            Err(err) => error_reply(err, StatusCode::OK),
+        }
+    }
+
+    /// An API error serializable to JSON.
+    #[derive(Serialize)]
+    struct ErrorMessage {
+        code: u16,
+        message: String,
+    }
+
+    /// ### References Codes
+    ///
+    /// - [warp](<https://github.com/seanmonstar/warp>)'s rejections (example)[<https://github.com/seanmonstar/warp/blob/master/examples/rejections.rs>].
+    ///
+    ///
+    // This function receives a `Rejection` and tries to return a custom
+    // value, otherwise simply passes the rejection along.
+    pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+        let code;
+        let message;
+        if let Some(unauthorized) = err.find::<Unauthorized>() {
+            let json = warp::reply::json(&ErrorMessage {
+                code: StatusCode::UNAUTHORIZED.as_u16(),
+                message: "this registry requires a valid token, please run `cargo login` first"
+                    .into(),
+            });
+            let mut resp = warp::reply::with_status(json, StatusCode::UNAUTHORIZED).into_response();
+            let challenge = match &unauthorized.login_url {
+                Some(login_url) => format!("Cargo login_url=\"{}\"", login_url),
+                None => "Cargo".to_owned(),
+            };
+            if let Ok(challenge) = challenge.parse() {
+                resp.headers_mut()
+                    .insert(http::header::WWW_AUTHENTICATE, challenge);
+            }
+            return Ok(resp);
+        } else if err.find::<TokenStoreError>().is_some() {
+            code = StatusCode::INTERNAL_SERVER_ERROR;
+            message = "failed to read tokens";
+        } else if err.is_not_found() {
+            code = StatusCode::NOT_FOUND;
+            message = "NOT_FOUND";
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            // This error happens if the body could not be deserialized correctly
+            // We can use the cause to analyze the error and customize the error message
+            message = match e.source() {
+                Some(cause) if cause.to_string().contains("denom") => "FIELD_ERROR: denom",
+                _ => "BAD_REQUEST",
+            };
+            code = StatusCode::BAD_REQUEST;
+        } else if err.find::<reject::PayloadTooLarge>().is_some() {
+            code = StatusCode::PAYLOAD_TOO_LARGE;
+            message = "PAYLOAD_TOO_LARGE";
+        } else if err.find::<reject::LengthRequired>().is_some() {
+            code = StatusCode::LENGTH_REQUIRED;
+            message = "LENGTH_REQUIRED";
+        } else if err.find::<reject::MethodNotAllowed>().is_some() {
+            // We can handle a specific error, here METHOD_NOT_ALLOWED,
+            // and render it however we want
+            code = StatusCode::METHOD_NOT_ALLOWED;
             message = "METHOD_NOT_ALLOWED";
         } else {
             // We should have expected this... Just log and say its a 500
@@ -401,7 +1352,7 @@ mod handlers {
             message: message.into(),
         });
 
-        Ok(warp::reply::with_status(json, code))
+        Ok(warp::reply::with_status(json, code).into_response())
     }
 
     pub async fn handle_missing_file(err: Rejection) -> Result<impl Reply, Rejection> {
@@ -413,64 +1364,1533 @@ mod handlers {
         Err(err)
     }
 
-    #[allow(unused)]
-    /// async download file from backup domain
-    pub async fn download_from_remote(path: PathBuf, uri: &Uri) -> FreightResult {
+    /// download file from upstream and stream it to the client while it's being written,
+    /// the file is moved to `path` only if its sha256 matches the checksum, and the
+    /// guard is released after that
+    pub async fn download_from_remote(
+        client: &Client,
+        path: PathBuf,
+        url: &str,
+        cksum: String,
+        guard: FlightGuard,
+    ) -> Result<Response<Body>, anyhow::Error> {
+        let mut remote = client.get(url).send().await?;
+        if !remote.status().is_success() {
+            return Err(anyhow!("upstream returned {} for {}", remote.status(), url));
+        }
+        let content_length = remote.content_length();
         if let Some(parent) = path.parent() {
-            if !parent.exists() {
-                tokio::fs::create_dir_all(parent).await?;
-            }
+            tokio::fs::create_dir_all(parent).await?;
         }
-        let mut resp = reqwest::get(uri.to_string()).await?;
-        if resp.status() == 200 {
-            let mut file = File::create(path).await?;
-            while let Some(mut data) = resp.chunk().await? {
-                file.write_all_buf(data.borrow_mut()).await?;
+        let tmp_path = utils::tmp_path(&path);
+        let mut file = File::create(&tmp_path).await?;
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut client_alive = true;
+            let mut hasher = Sha256::new();
+            let result: Result<(), anyhow::Error> = async {
+                while let Some(chunk) = remote.chunk().await? {
+                    hasher.update(&chunk);
+                    file.write_all(&chunk).await?;
+                    // keep downloading to fill the cache even if the client has gone
+                    if client_alive && sender.send_data(chunk).await.is_err() {
+                        client_alive = false;
+                    }
+                }
+                file.flush().await?;
+                let hex = format!("{:x}", hasher.finalize());
+                if hex != cksum {
+                    return Err(anyhow!("checksum mismatch, expect {} got {}", cksum, hex));
+                }
+                tokio::fs::rename(&tmp_path, &path).await?;
+                tracing::info!("&&&[NEW] \t\t {}", path.display());
+                Ok(())
             }
-            tracing::info!("{} {:?}", "&&&[NEW] \t\t ", file);
-        } else {
-            tracing::error!("download failed, Please check your url: {}", uri);
-            return Err(FreighterError::code(resp.status().as_u16().into()));
+            .await;
+            if let Err(err) = result {
+                tracing::error!("failed to cache {}: {:?}", path.display(), err);
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                // the client gets a broken body instead of a corrupted crate
+                sender.abort();
+            }
+            drop(guard);
+        });
+
+        let mut resp = Response::new(body);
+        if let Some(len) = content_length {
+            resp.headers_mut()
+                .insert(http::header::CONTENT_LENGTH, len.into());
         }
-        Ok(())
+        Ok(resp)
     }
 }
 
 mod utils {
-    use std::{fs, path::PathBuf};
+    use std::{
+        collections::BTreeSet,
+        fs::{self, OpenOptions},
+        io::{ErrorKind, Read, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use anyhow::anyhow;
+    use base64::{engine::general_purpose, Engine};
+    use chrono::{DateTime, Utc};
+    use flate2::read::GzDecoder;
+    use semver::Version;
+    use tar::Archive;
+    use warp::http::{header, HeaderMap};
 
     use crate::{
-        handler::{crates_file::IndexFile, utils},
-        server::model::CratesPublish,
+        config::RegistryMode,
+        handler::{crates_file::IndexFile, index, policy::Policy, utils},
+        server::{cache::is_valid_name, model::CratesPublish},
     };
-    use bytes::Bytes;
+    use bytes::{Buf, Bytes};
+    use serde::Deserialize;
     use sha2::{Digest, Sha256};
 
+    /// drop the lines of index file which are denied by the policy
+    pub fn filter_index_lines(content: &[u8], policy: &Policy) -> Vec<u8> {
+        #[derive(Deserialize)]
+        struct Version<'a> {
+            name: &'a str,
+            vers: &'a str,
+        }
+        let mut filtered = Vec::with_capacity(content.len());
+        for line in content.split_inclusive(|b| *b == b'\n') {
+            let allowed = match serde_json::from_slice::<Version>(line) {
+                Ok(version) => policy.is_allowed(version.name, version.vers),
+                Err(_) => !line.iter().all(|b| b.is_ascii_whitespace()),
+            };
+            if allowed {
+                filtered.extend_from_slice(line);
+            }
+        }
+        filtered
+    }
+
+    /// conditional headers are checked after the policy is applied, and ranges are dropped
+    /// because a partial body can't be filtered by line
+    pub fn without_conditionals(headers: &HeaderMap) -> HeaderMap {
+        let mut headers = headers.clone();
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+        headers.remove(header::RANGE);
+        headers.remove(header::IF_RANGE);
+        headers
+    }
+
+    /// find the checksum of the version in the index file
+    pub fn find_cksum(index_file: &Path, version: &str) -> Result<Option<String>, anyhow::Error> {
+        let content = fs::read_to_string(index_file)?;
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<IndexFile>(line).ok())
+            .find(|index| index.vers == version)
+            .and_then(|index| index.cksum))
+    }
+
+    /// a unique temp file next to the path, so concurrent downloads never write the same file
+    pub fn tmp_path(path: &Path) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        path.with_file_name(tmp_name)
+    }
+
+    /// a weak validator made from the size and modified time of a file, like nginx does,
+    /// the time has a resolution of one second so it can't tell apart two writes of the
+    /// same size in that second
+    pub fn file_etag(len: u64, modified: Option<DateTime<Utc>>) -> String {
+        let modified = modified.map(|m| m.timestamp()).unwrap_or_default();
+        format!("W/\"{:x}-{:x}\"", modified, len)
+    }
+
+    pub fn http_date(time: DateTime<Utc>) -> String {
+        time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    /// check the conditional headers, `If-None-Match` takes precedence over
+    /// `If-Modified-Since` as rfc 9110 requires
+    pub fn is_not_modified(
+        headers: &HeaderMap,
+        etag: &str,
+        modified: Option<DateTime<Utc>>,
+    ) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let if_none_match = if_none_match.to_str().unwrap_or_default();
+            // `If-None-Match` uses the weak comparison
+            let etag = etag.trim_start_matches("W/");
+            return if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            });
+        }
+        let since = headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+        match (since, modified) {
+            (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// parse a single range of `Range` header with the file length, return the inclusive
+    /// start and end, None if the header should be ignored like multiple ranges, and
+    /// error if the range is not satisfiable
+    pub fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+        let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if end.contains(',') {
+            return None;
+        }
+        let range = if start.is_empty() {
+            // the last `end` bytes
+            let suffix: u64 = end.parse().ok()?;
+            (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+        } else {
+            let start: u64 = start.parse().ok()?;
+            let end = match end {
+                "" => len.saturating_sub(1),
+                end => end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+            };
+            (start < len && start <= end).then_some((start, end))
+        };
+        Some(range.ok_or(()))
+    }
+
+    /// `If-Range` holds the validator of the file when the client got the first part,
+    /// it requires the strong comparison so a weak etag never matches
+    pub fn is_range_fresh(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+        match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+            Some(if_range) if if_range.starts_with("W/") || etag.starts_with("W/") => {
+                Some(if_range) == last_modified
+            }
+            Some(if_range) => if_range == etag || Some(if_range) == last_modified,
+            None => true,
+        }
+    }
+
+    /// get the token from the authorization header, cargo sends the token as it is
+    /// while git sends it as the password of basic auth
+    pub fn token_from_header(auth: Option<String>) -> Option<String> {
+        let auth = auth?;
+        let token = match auth.split_once(' ') {
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                let decoded = general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                decoded
+                    .split_once(':')
+                    .map(|(_, password)| password.to_owned())?
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("bearer") => {
+                credentials.trim().to_owned()
+            }
+            _ => auth,
+        };
+        (!token.is_empty()).then_some(token)
+    }
+
     pub fn get_usize_from_bytes(bytes: Bytes) -> usize {
         let mut fixed_array = [0u8; 8];
         fixed_array[..4].copy_from_slice(&bytes[..4]);
         usize::from_le_bytes(fixed_array)
     }
 
-    pub fn save_crate_index(json: &CratesPublish, content: &Bytes, work_dir: PathBuf) {
-        let suffix = utils::index_suffix(&json.name);
-        let index_path = work_dir.join(suffix);
-        //convert publish json to index file
-        let mut index_file: IndexFile =
-            serde_json::from_str(&serde_json::to_string(&json).unwrap()).unwrap();
+    /// split the publish request body into the metadata json and the crate file,
+    /// both of them are prefixed with a 32-bit little-endian length
+    pub fn split_publish_body(mut body: Bytes) -> Result<(Bytes, Bytes), anyhow::Error> {
+        let mut read_part = |part: &str| {
+            if body.remaining() < 4 {
+                return Err(anyhow!("invalid publish request: missing {} length", part));
+            }
+            let len = get_usize_from_bytes(body.copy_to_bytes(4));
+            if body.remaining() < len {
+                return Err(anyhow!(
+                    "invalid publish request: {} is truncated, expected {} bytes but got {}",
+                    part,
+                    len,
+                    body.remaining()
+                ));
+            }
+            Ok(body.copy_to_bytes(len))
+        };
+        let json = read_part("metadata")?;
+        let file_content = read_part("crate file")?;
+        Ok((json, file_content))
+    }
+
+    /// check the crate file is a gzipped tarball within the size limit, which contains
+    /// `{name}-{vers}/Cargo.toml` with the same package name and version as the metadata
+    // the same limit of crate name length as crates.io
+    const MAX_NAME_LENGTH: usize = 64;
+
+    /// check the crate name and version are legal before they are used in any path
+    pub fn verify_name_and_version(json: &CratesPublish) -> Result<(), anyhow::Error> {
+        if !is_valid_name(&json.name) || json.name.len() > MAX_NAME_LENGTH {
+            return Err(anyhow!(
+                "invalid crate name: `{}`, only ASCII alphanumeric characters, `-` and `_` \
+                 are allowed, up to {} characters",
+                json.name,
+                MAX_NAME_LENGTH
+            ));
+        }
+        if let Err(err) = Version::parse(&json.vers) {
+            return Err(anyhow!("invalid crate version `{}`: {}", json.vers, err));
+        }
+        Ok(())
+    }
+
+    // the unpacked crate file may be at most this many times the max upload size
+    const UNPACK_RATIO: usize = 20;
+
+    pub fn verify_crate_file(
+        json: &CratesPublish,
+        content: &Bytes,
+        max_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        if content.len() > max_size {
+            return Err(anyhow!(
+                "max upload size is: {} bytes, but the crate file is {} bytes",
+                max_size,
+                content.len()
+            ));
+        }
+        let manifest_path =
+            PathBuf::from(format!("{}-{}", json.name, json.vers)).join("Cargo.toml");
+        // bound the unpacked size, so a gzip bomb can't keep the decoder busy or fill the
+        // memory with a huge Cargo.toml
+        let unpacked = GzDecoder::new(content.as_ref()).take((max_size * UNPACK_RATIO) as u64);
+        let mut archive = Archive::new(unpacked);
+        let entries = archive
+            .entries()
+            .map_err(|err| anyhow!("invalid crate file: {}", err))?;
+        for entry in entries {
+            let entry = entry.map_err(|err| anyhow!("invalid crate file: {}", err))?;
+            if entry.path()? != manifest_path {
+                continue;
+            }
+            let mut manifest = String::new();
+            entry
+                .take(max_size as u64 + 1)
+                .read_to_string(&mut manifest)?;
+            if manifest.len() > max_size {
+                return Err(anyhow!("invalid crate file: Cargo.toml is too large"));
+            }
+            let manifest: toml::Value = toml::from_str(&manifest)
+                .map_err(|err| anyhow!("invalid Cargo.toml in crate file: {}", err))?;
+            let package = manifest.get("package");
+            let field = |key: &str| package.and_then(|p| p.get(key)).and_then(|v| v.as_str());
+            if field("name") != Some(json.name.as_str()) {
+                return Err(anyhow!(
+                    "package name in Cargo.toml doesn't match the metadata: `{}`",
+                    json.name
+                ));
+            }
+            if field("version") != Some(json.vers.as_str()) {
+                return Err(anyhow!(
+                    "package version in Cargo.toml doesn't match the metadata: `{}`",
+                    json.vers
+                ));
+            }
+            return Ok(());
+        }
+        Err(anyhow!(
+            "invalid crate file: `{}` not found",
+            manifest_path.display()
+        ))
+    }
+
+    // serialize all writes of index files, so concurrent publish and yank requests
+    // can't interleave their read-modify-write steps
+    static INDEX_LOCK: Mutex<()> = Mutex::new(());
 
+    /// append the published version to the crate's index file, the version is
+    /// rejected if it has been published before
+    pub fn save_crate_index(
+        json: &CratesPublish,
+        content: &Bytes,
+        work_dir: PathBuf,
+        mode: RegistryMode,
+    ) -> Result<(), anyhow::Error> {
+        // cargo requests the index file by the lowercased name
+        let suffix = utils::index_suffix(&json.name.to_lowercase());
+        let index_path = work_dir.join(&suffix);
         let mut hasher = Sha256::new();
         hasher.update(content);
-        index_file.cksum = Some(format!("{:x}", hasher.finalize()));
-        fs::write(index_path, serde_json::to_string(&index_file).unwrap()).unwrap();
+        let index_file = json.to_index_file(format!("{:x}", hasher.finalize()), mode);
+
+        let _guard = INDEX_LOCK.lock().unwrap();
+        let existing = match fs::read_to_string(&index_path) {
+            Ok(existing) => existing,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        for line in existing.lines().filter(|line| !line.is_empty()) {
+            let published: IndexFile = serde_json::from_str(line)?;
+            if is_same_version(&published.vers, &json.vers) {
+                return Err(anyhow!(
+                    "crate version `{}@{}` is already uploaded",
+                    json.name,
+                    published.vers
+                ));
+            }
+        }
+
+        if let Some(parent) = index_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_string(&index_file)?;
+        if !existing.is_empty() && !existing.ends_with('\n') {
+            line.insert(0, '\n');
+        }
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)?;
+        file.write_all(line.as_bytes())?;
+        let message = format!("Updating crate `{}#{}`", json.name, json.vers);
+        if let Err(err) = index::commit_index_file(&work_dir, Path::new(&suffix), &message) {
+            // put the file back, so the version isn't served without being published
+            let restored = if existing.is_empty() {
+                fs::remove_file(&index_path)
+            } else {
+                fs::write(&index_path, &existing)
+            };
+            if let Err(err) = restored
+                .map_err(anyhow::Error::from)
+                .and_then(|_| index::unstage_index_file(&work_dir, Path::new(&suffix)))
+            {
+                tracing::error!("restore index file {} failed: {:?}", suffix, err);
+            }
+            return Err(err);
+        }
+        Ok(())
     }
 
-    pub fn save_crate_file(json: &CratesPublish, content: &Bytes, work_dir: PathBuf) {
+    /// remove the line of a version saved by `save_crate_index`, when the publish fails after it
+    pub fn remove_crate_index(
+        json: &CratesPublish,
+        work_dir: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        let suffix = utils::index_suffix(&json.name.to_lowercase());
+        let index_path = work_dir.join(&suffix);
+        let _guard = INDEX_LOCK.lock().unwrap();
+        let content = fs::read_to_string(&index_path)?;
+        let mut lines = Vec::new();
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let index_file: IndexFile = serde_json::from_str(line)?;
+            if index_file.vers != json.vers {
+                lines.push(line);
+            }
+        }
+        if lines.is_empty() {
+            fs::remove_file(&index_path)?;
+        } else {
+            lines.push("");
+            fs::write(&index_path, lines.join("\n"))?;
+        }
+        let message = format!("Reverting crate `{}#{}`", json.name, json.vers);
+        index::commit_index_file(&work_dir, Path::new(&suffix), &message)
+    }
+
+    // versions differ only in build metadata are the same version for cargo
+    fn is_same_version(a: &str, b: &str) -> bool {
+        match (Version::parse(a), Version::parse(b)) {
+            (Ok(a), Ok(b)) => {
+                (a.major, a.minor, a.patch, a.pre) == (b.major, b.minor, b.patch, b.pre)
+            }
+            _ => a == b,
+        }
+    }
+
+    /// whether a crate of the name is in the index, names are compared like crates.io does,
+    /// ignoring case and treating `-` and `_` as the same
+    pub fn is_name_taken(index_path: &Path, name: &str) -> bool {
+        if !is_valid_name(name) {
+            return false;
+        }
+        let canonical = |name: &str| name.to_lowercase().replace('_', "-");
+        let wanted = canonical(name);
+        // `-` and `_` in the first four characters change the directory of the index file
+        let mut variants = vec![String::new()];
+        for (i, c) in wanted.chars().enumerate() {
+            let choices: &[char] = if c == '-' && i < 4 { &['-', '_'] } else { &[c] };
+            variants = variants
+                .iter()
+                .flat_map(|v| choices.iter().map(move |c| format!("{}{}", v, c)))
+                .collect();
+        }
+        let dirs: BTreeSet<PathBuf> = variants
+            .iter()
+            .filter_map(|v| {
+                index_path
+                    .join(utils::index_suffix(v))
+                    .parent()
+                    .map(Path::to_path_buf)
+            })
+            .collect();
+        dirs.iter()
+            .filter_map(|dir| fs::read_dir(dir).ok())
+            .any(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .any(|entry| canonical(&entry.file_name().to_string_lossy()) == wanted)
+            })
+    }
+
+    /// update the yanked field of the index line which matches the given version,
+    /// other lines in the index file are kept untouched
+    pub fn set_crate_yanked(
+        work_dir: PathBuf,
+        name: &str,
+        version: &str,
+        yanked: bool,
+    ) -> Result<(), anyhow::Error> {
+        let suffix = utils::index_suffix(&name.to_lowercase());
+        let index_path = work_dir.join(&suffix);
+        let _guard = INDEX_LOCK.lock().unwrap();
+        let content = fs::read_to_string(&index_path)
+            .map_err(|_| anyhow!("crate `{}` does not exist", name))?;
+
+        let mut found = false;
+        let mut lines = Vec::new();
+        for line in content.lines() {
+            let mut index_file: IndexFile = serde_json::from_str(line)?;
+            if index_file.vers == version {
+                index_file.yanked = Some(yanked);
+                lines.push(serde_json::to_string(&index_file)?);
+                found = true;
+            } else {
+                lines.push(line.to_owned());
+            }
+        }
+        if !found {
+            return Err(anyhow!(
+                "crate `{}` does not have a version `{}`",
+                name,
+                version
+            ));
+        }
+        lines.push(String::new());
+        fs::write(index_path, lines.join("\n"))?;
+        let action = if yanked { "Yanking" } else { "Unyanking" };
+        let message = format!("{} crate `{}#{}`", action, name, version);
+        index::commit_index_file(&work_dir, Path::new(&suffix), &message)
+    }
+
+    /// write the crate file to a hidden staging file and return its path, it's renamed to
+    /// `{name}-{vers}.crate` after the index line is saved, so a rejected duplicate version
+    /// never overwrites the published file
+    pub fn save_crate_file(
+        json: &CratesPublish,
+        content: &Bytes,
+        work_dir: PathBuf,
+    ) -> Result<PathBuf, anyhow::Error> {
         let crates_dir = work_dir.join(&json.name);
         if !crates_dir.exists() {
-            fs::create_dir_all(&crates_dir).unwrap();
+            fs::create_dir_all(&crates_dir)?;
+        }
+        // concurrent uploads of the same version must not share the staging file
+        static STAGED_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = STAGED_ID.fetch_add(1, Ordering::Relaxed);
+        let staged = crates_dir.join(format!(".{}-{}.crate.{}", json.name, json.vers, id));
+        fs::write(&staged, content)?;
+        Ok(staged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use sha2::{Digest, Sha256};
+    use warp::{Filter, Reply};
+
+    use crate::{
+        config::{Config, GitBackend, RegistryConfig, RegistryMode},
+        handler::{
+            crates_file::IndexFile,
+            index::{self, IndexConfig},
+            policy::{Policy, Rule},
+            utils::index_suffix,
+        },
+        server::{model::CratesPublish, tokens::TokenStore},
+    };
+
+    use super::{filters, handlers, utils};
+
+    fn publish_json(vers: &str) -> CratesPublish {
+        serde_json::from_value(serde_json::json!({
+            "name": "foo", "vers": vers, "deps": [], "features": {}, "authors": [],
+            "description": null, "documentation": null, "homepage": null, "readme": null,
+            "readme_file": null, "keywords": [], "categories": [], "license": "MIT",
+            "license_file": null, "repository": null, "badges": {}, "links": null
+        }))
+        .unwrap()
+    }
+
+    fn crate_tarball(path: &str, manifest: &str) -> Bytes {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, manifest.as_bytes())
+            .unwrap();
+        Bytes::from(builder.into_inner().unwrap().finish().unwrap())
+    }
+
+    #[test]
+    fn test_verify_crate_file() {
+        let json = publish_json("0.1.0");
+        let manifest = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n";
+        let content = crate_tarball("foo-0.1.0/Cargo.toml", manifest);
+        assert!(utils::verify_crate_file(&json, &content, 1024 * 1024).is_ok());
+        assert!(utils::verify_crate_file(&json, &content, 10).is_err());
+
+        let content = crate_tarball("foo-0.2.0/Cargo.toml", manifest);
+        assert!(utils::verify_crate_file(&json, &content, 1024 * 1024).is_err());
+
+        let manifest = "[package]\nname = \"bar\"\nversion = \"0.1.0\"\n";
+        let content = crate_tarball("foo-0.1.0/Cargo.toml", manifest);
+        assert!(utils::verify_crate_file(&json, &content, 1024 * 1024).is_err());
+
+        let truncated = content.slice(..content.len() / 2);
+        assert!(utils::verify_crate_file(&json, &truncated, 1024 * 1024).is_err());
+
+        // a small upload that unpacks to far more than the limit allows
+        let bomb = crate_tarball("foo-0.1.0/Cargo.toml", &" ".repeat(1024 * 1024));
+        assert!(bomb.len() < 4096);
+        assert!(utils::verify_crate_file(&json, &bomb, 4096).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_invalid_name() {
+        let work_dir = env::temp_dir().join("freighter-test-publish-invalid");
+        let _ = fs::remove_dir_all(&work_dir);
+        let mut config = Config::new();
+        config.index_path = work_dir.join("index");
+        config.crates_path = work_dir.join("crates");
+        let route = filters::publish(config, RegistryMode::Mirror);
+
+        for (name, vers) in [("../x", "0.1.0"), ("ünï", "0.1.0"), ("foo", "0.1.0/../x")] {
+            let mut json = serde_json::to_value(publish_json(vers)).unwrap();
+            json["name"] = name.into();
+            let json = serde_json::to_vec(&json).unwrap();
+            // the tarball is never opened, the name and version are rejected first
+            let content = crate_tarball("foo-0.1.0/Cargo.toml", "[package]\n");
+            let mut body = (json.len() as u32).to_le_bytes().to_vec();
+            body.extend_from_slice(&json);
+            body.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            body.extend_from_slice(&content);
+            let res = warp::test::request()
+                .method("PUT")
+                .path("/api/v1/crates/new")
+                .body(body)
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), 400, "{}@{}", name, vers);
+            let errors: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert!(errors["errors"][0]["detail"].is_string());
+        }
+        assert!(!work_dir.exists());
+    }
+
+    #[test]
+    fn test_split_publish_body() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&2u32.to_le_bytes());
+        body.extend_from_slice(b"{}");
+        body.extend_from_slice(&5u32.to_le_bytes());
+        body.extend_from_slice(b"crate");
+        let (json, file) = utils::split_publish_body(Bytes::from(body.clone())).unwrap();
+        assert_eq!((json.as_ref(), file.as_ref()), (&b"{}"[..], &b"crate"[..]));
+
+        body.truncate(body.len() - 1);
+        assert!(utils::split_publish_body(Bytes::from(body)).is_err());
+    }
+
+    #[test]
+    fn test_save_crate_index() {
+        let work_dir = env::temp_dir().join("freighter-test-publish");
+        let _ = fs::remove_dir_all(&work_dir);
+        let content = Bytes::from_static(b"crate");
+        let mode = RegistryMode::Mirror;
+        utils::save_crate_index(&publish_json("0.1.0"), &content, work_dir.clone(), mode).unwrap();
+        utils::save_crate_index(&publish_json("0.2.0"), &content, work_dir.clone(), mode).unwrap();
+        let err = utils::save_crate_index(
+            &publish_json("0.2.0+build"),
+            &content,
+            work_dir.clone(),
+            mode,
+        );
+        assert!(err.unwrap_err().to_string().contains("already uploaded"));
+
+        let index = fs::read_to_string(work_dir.join(index_suffix("foo"))).unwrap();
+        let versions: Vec<String> = index
+            .lines()
+            .map(|line| serde_json::from_str::<IndexFile>(line).unwrap().vers)
+            .collect();
+        assert_eq!(versions, vec!["0.1.0", "0.2.0"]);
+
+        let repo = git2::Repository::open(&work_dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("Updating crate `foo#0.2.0`"));
+        assert_eq!(head.parent_count(), 1);
+
+        // a failed commit leaves the index file as it was
+        fs::write(work_dir.join(".git/index.lock"), "").unwrap();
+        let err = utils::save_crate_index(&publish_json("0.3.0"), &content, work_dir.clone(), mode);
+        assert!(err.is_err());
+        fs::remove_file(work_dir.join(".git/index.lock")).unwrap();
+        assert_eq!(
+            fs::read_to_string(work_dir.join(index_suffix("foo"))).unwrap(),
+            index
+        );
+
+        utils::remove_crate_index(&publish_json("0.2.0"), work_dir.clone()).unwrap();
+        let index = fs::read_to_string(work_dir.join(index_suffix("foo"))).unwrap();
+        assert_eq!(index.lines().count(), 1);
+        assert!(index.contains("\"vers\":\"0.1.0\""));
+        utils::remove_crate_index(&publish_json("0.1.0"), work_dir.clone()).unwrap();
+        assert!(!work_dir.join(index_suffix("foo")).exists());
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("Reverting crate `foo#0.1.0`"));
+        assert!(head.tree().unwrap().get_path(Path::new("3/f/foo")).is_err());
+
+        let mut json = publish_json("0.1.0");
+        json.name = "MyCrate".to_owned();
+        utils::save_crate_index(&json, &content, work_dir.clone(), mode).unwrap();
+        assert!(work_dir.join("my/cr/mycrate").exists());
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_name_taken() {
+        let work_dir = env::temp_dir().join("freighter-test-name-taken");
+        let _ = fs::remove_dir_all(&work_dir);
+        for name in ["serde", "a_b", "foo_bar"] {
+            let index_path = work_dir.join(index_suffix(name));
+            fs::create_dir_all(index_path.parent().unwrap()).unwrap();
+            fs::write(index_path, "").unwrap();
+        }
+        for name in [
+            "serde", "Serde", "SERDE", "a-b", "A_B", "foo-bar", "Foo_Bar",
+        ] {
+            assert!(utils::is_name_taken(&work_dir, name), "{}", name);
+        }
+        for name in ["serde2", "ab", "foo", "foobar"] {
+            assert!(!utils::is_name_taken(&work_dir, name), "{}", name);
+        }
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_crate_yanked() {
+        let work_dir = env::temp_dir().join("freighter-test-yank");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join(index_suffix("foo"));
+        fs::create_dir_all(index_path.parent().unwrap()).unwrap();
+        fs::write(
+            &index_path,
+            concat!(
+                r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#,
+                "\n",
+                r#"{"name":"foo","vers":"0.2.0","deps":[],"cksum":"bb","features":{},"yanked":false}"#,
+                "\n"
+            ),
+        )
+        .unwrap();
+
+        utils::set_crate_yanked(work_dir.clone(), "foo", "0.2.0", true).unwrap();
+        let read_yanked = || -> Vec<Option<bool>> {
+            fs::read_to_string(&index_path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<IndexFile>(line).unwrap().yanked)
+                .collect()
+        };
+        assert_eq!(read_yanked(), vec![Some(false), Some(true)]);
+
+        utils::set_crate_yanked(work_dir.clone(), "foo", "0.2.0", false).unwrap();
+        assert_eq!(read_yanked(), vec![Some(false), Some(false)]);
+
+        // the index file is found by the lowercased name
+        utils::set_crate_yanked(work_dir.clone(), "Foo", "0.1.0", true).unwrap();
+        assert_eq!(read_yanked(), vec![Some(true), Some(false)]);
+
+        assert!(utils::set_crate_yanked(work_dir.clone(), "foo", "0.3.0", true).is_err());
+        assert!(utils::set_crate_yanked(work_dir.clone(), "bar", "0.1.0", true).is_err());
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_index_config() {
+        let mut config = Config::new();
+        config.server.public_url = "https://freighter.example.com/".to_owned();
+        let res = warp::test::request()
+            .path("/index/config.json")
+            .reply(&filters::sparse_index(config, Arc::default()))
+            .await;
+        assert_eq!(res.status(), 200);
+        let index_config: IndexConfig = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(index_config.dl, "https://freighter.example.com/crates");
+        assert_eq!(index_config.api, "https://freighter.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_auth_required() {
+        let work_dir = env::temp_dir().join("freighter-test-auth-required");
+        let _ = fs::remove_dir_all(&work_dir);
+        let mut config = Config::new();
+        config.server.auth_required = true;
+        config.tokens_path = work_dir.clone();
+        let token = TokenStore::new(work_dir.clone())
+            .create("alice", "ci")
+            .unwrap();
+        let route = filters::sparse_index(config.clone(), Arc::default())
+            .recover(handlers::handle_rejection);
+
+        let res = warp::test::request()
+            .path("/index/config.json")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers()["www-authenticate"], "Cargo");
+
+        config.server.login_url = "https://freighter.example.com/tokens".to_owned();
+        let login_route =
+            filters::sparse_index(config, Arc::default()).recover(handlers::handle_rejection);
+        let res = warp::test::request()
+            .path("/index/config.json")
+            .reply(&login_route)
+            .await;
+        assert_eq!(
+            res.headers()["www-authenticate"],
+            "Cargo login_url=\"https://freighter.example.com/tokens\""
+        );
+
+        let res = warp::test::request()
+            .path("/index/config.json")
+            .header("Authorization", &token)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        let index_config: IndexConfig = serde_json::from_slice(res.body()).unwrap();
+        assert!(index_config.auth_required);
+
+        // an unreadable token store is a server error, not a bad token
+        fs::write(work_dir.join("tokens.json"), "not json").unwrap();
+        let res = warp::test::request()
+            .path("/index/config.json")
+            .header("Authorization", &token)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 500);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn test_token_from_header() {
+        assert_eq!(utils::token_from_header(None), None);
+        assert_eq!(
+            utils::token_from_header(Some("abc".to_owned())),
+            Some("abc".to_owned())
+        );
+        // git sends `user:token` with basic auth
+        assert_eq!(
+            utils::token_from_header(Some("Basic dXNlcjphYmM=".to_owned())),
+            Some("abc".to_owned())
+        );
+        assert_eq!(
+            utils::token_from_header(Some("Bearer abc".to_owned())),
+            Some("abc".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conditional_request() {
+        let work_dir = env::temp_dir().join("freighter-test-conditional");
+        let _ = fs::remove_dir_all(&work_dir);
+        fs::create_dir_all(work_dir.join("dist")).unwrap();
+        fs::write(work_dir.join("dist/channel-rust-stable.toml"), "manifest").unwrap();
+        let mut config = Config::new();
+        config.dist_path = work_dir.clone();
+        config.rustup.serve_domains = Some(vec!["localhost".to_owned()]);
+        let route = filters::dist(config);
+
+        let path = "/dist/channel-rust-stable.toml";
+        let res = warp::test::request().path(path).reply(&route).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body().as_ref(), b"manifest");
+        let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+        let last_modified = res.headers()["last-modified"].to_str().unwrap().to_owned();
+
+        let res = warp::test::request()
+            .path(path)
+            .header("If-None-Match", &etag)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+
+        let res = warp::test::request()
+            .path(path)
+            .header("If-Modified-Since", &last_modified)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 304);
+
+        // a stale etag wins over a matched date
+        let res = warp::test::request()
+            .path(path)
+            .header("If-None-Match", "\"stale\"")
+            .header("If-Modified-Since", &last_modified)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_through_crate() {
+        let work_dir = env::temp_dir().join("freighter-test-pull-through-crate");
+        let _ = fs::remove_dir_all(&work_dir);
+        let content = crate_tarball("foo-0.1.0/Cargo.toml", "[package]\n");
+        let cksum = format!("{:x}", Sha256::digest(&content));
+        // 0.2.0 is served with wrong content
+        let index = format!(
+            "{{\"name\":\"foo\",\"vers\":\"0.1.0\",\"deps\":[],\"cksum\":\"{}\",\"features\":{{}},\"yanked\":false}}\n\
+             {{\"name\":\"foo\",\"vers\":\"0.2.0\",\"deps\":[],\"cksum\":\"{}\",\"features\":{{}},\"yanked\":false}}\n",
+            cksum, cksum
+        );
+        let upstream_content = content.clone();
+        let upstream = warp::path!("3" / "f" / "foo")
+            .map(move || index.clone())
+            .or(
+                warp::path!("crates" / "foo" / String).map(move |file: String| {
+                    if file == "foo-0.1.0.crate" {
+                        upstream_content.to_vec()
+                    } else {
+                        b"corrupted".to_vec()
+                    }
+                }),
+            );
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut config = Config::new();
+        config.index_path = work_dir.join("index");
+        config.crates_path = work_dir.join("crates");
+        config.crates.pull_through = true;
+        config.crates.sparse_index_domain = format!("http://{}", addr);
+        config.crates.domain = format!("http://{}/crates", addr);
+        let index_route = filters::sparse_index(config.clone(), Arc::default());
+        let route = filters::crates(config, Arc::default());
+
+        let res = warp::test::request()
+            .path("/crates/foo/0.1.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), &content);
+        let cached = work_dir.join("crates/foo/foo-0.1.0.crate");
+        for _ in 0..50 {
+            if cached.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
-        let crates_file = crates_dir.join(format!("{}-{}.crate", json.name, json.vers));
-        fs::write(crates_file, content).unwrap();
+        assert_eq!(fs::read(&cached).unwrap(), content.to_vec());
+
+        // corrupted crate is never saved
+        let res = warp::test::request()
+            .path("/crates/foo/foo-0.2.0.crate")
+            .filter(&route)
+            .await
+            .unwrap()
+            .into_response();
+        assert!(warp::hyper::body::to_bytes(res.into_body()).await.is_err());
+        assert!(!work_dir.join("crates/foo/foo-0.2.0.crate").exists());
+
+        let res = warp::test::request()
+            .path("/crates/foo/0.3.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 404);
+
+        // a path that can't be an index file is not sent upstream
+        for path in ["/index/3/b/foo", "/index/3/f/fo~"] {
+            let res = warp::test::request().path(path).reply(&index_route).await;
+            assert_eq!(res.status(), 404, "{}", path);
+        }
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_crate_fetch() {
+        let work_dir = env::temp_dir().join("freighter-test-coalesce-crate");
+        let _ = fs::remove_dir_all(&work_dir);
+        let content = crate_tarball("bar-0.1.0/Cargo.toml", "[package]\n");
+        let index = format!(
+            "{{\"name\":\"bar\",\"vers\":\"0.1.0\",\"deps\":[],\"cksum\":\"{:x}\",\"features\":{{}},\"yanked\":false}}\n",
+            Sha256::digest(&content)
+        );
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (upstream_content, upstream_hits) = (content.clone(), hits.clone());
+        let upstream = warp::path!("3" / "b" / "bar")
+            .map(move || index.clone())
+            .or(
+                warp::path!("crates" / "bar" / "bar-0.1.0.crate").then(move || {
+                    let (content, hits) = (upstream_content.clone(), upstream_hits.clone());
+                    async move {
+                        hits.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        content.to_vec()
+                    }
+                }),
+            );
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut config = Config::new();
+        config.index_path = work_dir.join("index");
+        config.crates_path = work_dir.join("crates");
+        config.crates.pull_through = true;
+        config.crates.sparse_index_domain = format!("http://{}", addr);
+        config.crates.domain = format!("http://{}/crates", addr);
+        let route = filters::crates(config, Arc::default());
+
+        let requests: Vec<_> = (0..5)
+            .map(|_| {
+                let route = route.clone();
+                tokio::spawn(async move {
+                    warp::test::request()
+                        .path("/crates/bar/0.1.0/download")
+                        .reply(&route)
+                        .await
+                })
+            })
+            .collect();
+        for request in requests {
+            let res = request.await.unwrap();
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), &content);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(utils::parse_range("bytes=0-4", 10), Some(Ok((0, 4))));
+        assert_eq!(utils::parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(utils::parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(utils::parse_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(utils::parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(utils::parse_range("bytes=0-1,5-6", 10), None);
+        assert_eq!(utils::parse_range("items=0-1", 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_range_request() {
+        let work_dir = env::temp_dir().join("freighter-test-range");
+        let _ = fs::remove_dir_all(&work_dir);
+        fs::create_dir_all(work_dir.join("rustup/dist")).unwrap();
+        fs::write(work_dir.join("rustup/dist/rustup-init"), "0123456789").unwrap();
+        let mut config = Config::new();
+        config.rustup_path = work_dir.clone();
+        config.rustup.serve_domains = Some(vec!["localhost".to_owned()]);
+        let route = filters::rustup(config);
+
+        let path = "/rustup/dist/rustup-init";
+        let res = warp::test::request().path(path).reply(&route).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["accept-ranges"], "bytes");
+        let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+        let last_modified = res.headers()["last-modified"].to_str().unwrap().to_owned();
+
+        // the etag is weak, so only the date can validate the range
+        let res = warp::test::request()
+            .path(path)
+            .header("Range", "bytes=4-")
+            .header("If-Range", &etag)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = warp::test::request()
+            .path(path)
+            .header("Range", "bytes=4-")
+            .header("If-Range", &last_modified)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 206);
+        assert_eq!(res.headers()["content-range"], "bytes 4-9/10");
+        assert_eq!(res.body().as_ref(), b"456789");
+
+        let res = warp::test::request()
+            .path(path)
+            .header("Range", "bytes=20-")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 416);
+        assert_eq!(res.headers()["content-range"], "bytes */10");
+
+        // the file has changed, so the whole file is sent
+        let res = warp::test::request()
+            .path(path)
+            .header("Range", "bytes=4-")
+            .header("If-Range", "\"stale\"")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body().as_ref(), b"0123456789");
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_from_git() {
+        let work_dir = env::temp_dir().join("freighter-test-serve-from-git");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("crates.io-index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::write(index_path.join("3/f/foo"), "v1\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        index::advance_served_ref(&index_path).unwrap();
+        // the working tree is being changed by a pull
+        fs::write(index_path.join("3/f/foo"), "v1\nv2").unwrap();
+
+        let mut config = Config::new();
+        config.index_path = index_path;
+        config.crates.serve_from_git = true;
+        let route = filters::sparse_index(config, Arc::default());
+
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body().as_ref(), b"v1\n");
+        let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .header("If-None-Match", &etag)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 304);
+        let res = warp::test::request()
+            .path("/index/3/b/bar")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 404);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let work_dir = env::temp_dir().join("freighter-test-snapshots-route");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("crates.io-index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::write(index_path.join("3/f/foo"), "v1\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        let repo = git2::Repository::open(&index_path).unwrap();
+        let commit = repo.head().unwrap().peel_to_commit().unwrap().id();
+        fs::write(index_path.join("3/f/foo"), "v1\nv2\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        fs::create_dir_all(work_dir.join("log")).unwrap();
+        fs::write(
+            work_dir.join("log/2024-01-01-record.log"),
+            format!("0000000,{},1704110400\n", commit),
+        )
+        .unwrap();
+
+        let mut config = Config::new();
+        config.index_path = index_path;
+        config.log_path = work_dir.join("log");
+        let route = filters::snapshots(config, Arc::default());
+
+        let res = warp::test::request().path("/snapshots").reply(&route).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["snapshots"][0]["commit"], commit.to_string());
+
+        for id in ["2024-01-01", &commit.to_string()[..7]] {
+            let res = warp::test::request()
+                .path(&format!("/snapshots/{}/index/3/f/foo", id))
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body().as_ref(), b"v1\n");
+        }
+        let res = warp::test::request()
+            .path("/snapshots/2024-01-01/index/config.json")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = warp::test::request()
+            .path("/snapshots/2023-01-01/index/3/f/foo")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 404);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_crates_policy() {
+        let work_dir = env::temp_dir().join("freighter-test-crates-policy");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("crates.io-index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::write(
+            index_path.join("3/f/foo"),
+            "{\"name\":\"foo\",\"vers\":\"0.1.0\"}\n{\"name\":\"foo\",\"vers\":\"0.2.0\"}\n",
+        )
+        .unwrap();
+        fs::write(
+            work_dir.join("policy.toml"),
+            "[[deny]]\nname = \"foo\"\nversion = \">=0.2\"\nreason = \"malware\"\n",
+        )
+        .unwrap();
+        let mut config = Config::new();
+        config.index_path = index_path;
+        config.crates.serve_domains = Some(vec!["localhost".to_owned()]);
+        config.crates.policy_path = Some(work_dir.join("policy.toml"));
+        let policy = Arc::new(Policy::load(&config.crates.policy_path).unwrap());
+
+        let route = filters::sparse_index(config.clone(), policy.clone());
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body().as_ref(),
+            b"{\"name\":\"foo\",\"vers\":\"0.1.0\"}\n"
+        );
+        let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .header("If-None-Match", &etag)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 304);
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .header("Range", "bytes=0-")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body().as_ref(),
+            b"{\"name\":\"foo\",\"vers\":\"0.1.0\"}\n"
+        );
+
+        let route = filters::crates(config, policy);
+        let res = warp::test::request()
+            .path("/crates/foo/0.2.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 403);
+        assert!(String::from_utf8_lossy(res.body()).contains("malware"));
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    // clone the served index with git, returns the stderr with packet trace
+    async fn git_clone(url: &str, dest: &Path, args: &[&str]) -> String {
+        let output = tokio::process::Command::new("git")
+            .arg("clone")
+            .args(args)
+            .arg(url)
+            .arg(dest)
+            .env("GIT_TRACE_PACKET", "1")
+            .output()
+            .await
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        assert!(output.status.success(), "{}", stderr);
+        stderr
+    }
+
+    #[tokio::test]
+    async fn test_git_clone() {
+        let work_dir = env::temp_dir().join("freighter-test-git-clone");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("crates.io-index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        fs::write(index_path.join("3/f/foo"), "v1\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        fs::write(index_path.join("3/f/foo"), "v1\nv2\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+
+        let mut config = Config::new();
+        config.index_path = index_path;
+        let (addr, server) = warp::serve(filters::git(config)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/crates.io-index", addr);
+
+        let dest = work_dir.join("v2-shallow");
+        let trace = git_clone(&url, &dest, &["-c", "protocol.version=2", "--depth", "1"]).await;
+        assert!(trace.contains("version 2"));
+        assert_eq!(
+            fs::read_to_string(dest.join("3/f/foo")).unwrap(),
+            "v1\nv2\n"
+        );
+        assert!(dest.join(".git/shallow").exists());
+
+        let dest = work_dir.join("v2-filter");
+        git_clone(
+            &url,
+            &dest,
+            &["-c", "protocol.version=2", "--filter=blob:none"],
+        )
+        .await;
+        assert_eq!(
+            fs::read_to_string(dest.join("3/f/foo")).unwrap(),
+            "v1\nv2\n"
+        );
+        let repo = git2::Repository::open(&dest).unwrap();
+        assert_eq!(
+            repo.config()
+                .unwrap()
+                .get_string("remote.origin.promisor")
+                .unwrap(),
+            "true"
+        );
+
+        let dest = work_dir.join("v0");
+        let trace = git_clone(&url, &dest, &["-c", "protocol.version=0"]).await;
+        assert!(!trace.contains("version 2"));
+        assert_eq!(
+            fs::read_to_string(dest.join("3/f/foo")).unwrap(),
+            "v1\nv2\n"
+        );
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_builtin_git_clone() {
+        let work_dir = env::temp_dir().join("freighter-test-builtin-git-clone");
+        let _ = fs::remove_dir_all(&work_dir);
+        let index_path = work_dir.join("crates.io-index");
+        fs::create_dir_all(index_path.join("3/f")).unwrap();
+        for content in ["v1\n", "v1\nv2\n"] {
+            fs::write(index_path.join("3/f/foo"), content).unwrap();
+            index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        }
+
+        let mut config = Config::new();
+        config.index_path = index_path.clone();
+        config.crates.git_backend = GitBackend::Builtin;
+        let (addr, server) =
+            warp::serve(filters::git(config.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/crates.io-index", addr);
+
+        let full = work_dir.join("full");
+        git_clone(&url, &full, &[]).await;
+        let shallow = work_dir.join("shallow");
+        git_clone(&url, &shallow, &["--depth", "1"]).await;
+        assert_eq!(
+            fs::read_to_string(shallow.join("3/f/foo")).unwrap(),
+            "v1\nv2\n"
+        );
+        assert!(shallow.join(".git/shallow").exists());
+
+        // fetch the new commit with the haves of the clones
+        fs::write(index_path.join("3/f/foo"), "v1\nv2\nv3\n").unwrap();
+        index::commit_index_file(&index_path, Path::new("3/f/foo"), "foo").unwrap();
+        for (dest, args) in [
+            (&full, vec!["pull"]),
+            (&shallow, vec!["fetch", "--depth", "3"]),
+        ] {
+            let output = tokio::process::Command::new("git")
+                .args(args)
+                .current_dir(dest)
+                .output()
+                .await
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        assert_eq!(
+            fs::read_to_string(full.join("3/f/foo")).unwrap(),
+            "v1\nv2\nv3\n"
+        );
+        let repo = git2::Repository::open(&shallow).unwrap();
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_ref("refs/remotes/origin/master").unwrap();
+        assert_eq!(walk.count(), 3);
+
+        let res = warp::test::request()
+            .path("/crates.io-index/info/refs")
+            .reply(&filters::git(config))
+            .await;
+        assert_eq!(res.status(), 403);
+        fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_registries() {
+        let work_dir = env::temp_dir().join("freighter-test-registries");
+        let _ = fs::remove_dir_all(&work_dir);
+        let mut config = Config::new();
+        config.index_path = work_dir.join("crates.io-index");
+        config.crates_path = work_dir.join("crates");
+        config.owners_path = work_dir.join("owners");
+        config.tokens_path = work_dir.join("tokens");
+        config.search_path = work_dir.join("search");
+        config.crates.serve_domains = Some(vec!["https://static.crates.io".to_owned()]);
+        config.crates.max_upload_size = 1;
+        for (name, mode) in [
+            ("team", RegistryMode::Private),
+            ("crates", RegistryMode::Private),
+            ("mirror", RegistryMode::Mirror),
+        ] {
+            config.registries.push(RegistryConfig {
+                name: name.to_owned(),
+                index_path: Some(work_dir.join(name).join("index")),
+                crates_path: Some(work_dir.join(name).join("crates")),
+                mode,
+            });
+        }
+        let token = TokenStore::new(config.tokens_path.clone())
+            .create("alice", "ci")
+            .unwrap();
+        let mirror_index = work_dir.join("mirror/index/3/b/bar");
+        fs::create_dir_all(mirror_index.parent().unwrap()).unwrap();
+        fs::write(mirror_index, "{\"name\":\"bar\",\"vers\":\"0.1.0\"}\n").unwrap();
+        // an allowlist of crates.io crates hides mirrored crates but not published ones
+        let policy = Policy {
+            allow: vec![Rule {
+                name: "serde".to_owned(),
+                version: None,
+                reason: None,
+            }],
+            deny: Vec::new(),
+        };
+        let route = filters::build_route(config.clone(), Arc::new(policy));
+
+        // a dependency from crates.io keeps its registry in the index of a private registry
+        let mut json = serde_json::to_value(publish_json("0.1.0")).unwrap();
+        json["deps"] = serde_json::json!([{
+            "name": "serde", "version_req": "^1", "features": [], "optional": false,
+            "default_features": true, "target": null, "kind": "normal",
+            "registry": "https://github.com/rust-lang/crates.io-index"
+        }]);
+        let json = serde_json::to_vec(&json).unwrap();
+        let content = crate_tarball(
+            "foo-0.1.0/Cargo.toml",
+            "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+        );
+        let mut body = (json.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&json);
+        body.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        body.extend_from_slice(&content);
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/team/api/v1/crates/new")
+            .header("Authorization", &token)
+            .body(body)
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200, "{:?}", res.body());
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/team/api/v1/crates/new")
+            .header("Authorization", &token)
+            .body(vec![0; 3 * 1024 * 1024])
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 413);
+
+        let res = warp::test::request()
+            .path("/team/index/config.json")
+            .reply(&route)
+            .await;
+        let index_config: IndexConfig = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(index_config.dl, "http://localhost:8000/team/crates");
+        assert_eq!(index_config.api, "http://localhost:8000/team");
+        let res = warp::test::request()
+            .path("/team/index/3/f/foo")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        let index_file: IndexFile = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            index_file.deps[0].registry.as_deref(),
+            Some("https://github.com/rust-lang/crates.io-index")
+        );
+        let res = warp::test::request()
+            .path("/team/crates/foo/0.1.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.body(), &content);
+        assert!(work_dir.join("owners/team/3/f/foo.json").exists());
+        let res = warp::test::request()
+            .path("/team/index/info/refs?service=git-upload-pack")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(res.body().starts_with(b"001e# service=git-upload-pack"));
+
+        // the crate is not in the local crates.io mirror, so the mirror redirects to its serve
+        // domains while a private registry never redirects
+        let res = warp::test::request()
+            .path("/index/3/f/foo")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 302);
+        let res = warp::test::request()
+            .path("/team/crates/bar/0.1.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 404);
+        let res = warp::test::request()
+            .path("/mirror/index/3/b/bar")
+            .reply(&route)
+            .await;
+        assert_ne!(res.status(), 200);
+        let registry_config = config.for_registry(&config.registries[0]);
+        assert_eq!(
+            registry_config.log_path,
+            work_dir.join("registries/team/log")
+        );
+        // a registry can't shadow the routes of the mirror
+        let res = warp::test::request()
+            .path("/crates/serde/1.0.0/download")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), 302);
+        fs::remove_dir_all(work_dir).unwrap();
     }
 }
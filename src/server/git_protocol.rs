@@ -1,161 +1,250 @@
+//! the upload-pack side of git smart http protocol, which serves `git clone` and `git fetch`
+//! of the index, it's handled by spawning `git http-backend` or by the builtin implementation
+//! on top of libgit2, see [`UploadPack`]
+//!
+
 #![allow(incomplete_features)]
-use bytes::{Buf, BufMut, BytesMut};
-use std::{collections::HashMap, path::PathBuf, process::Stdio};
+use std::{io::ErrorKind, path::PathBuf, process::Stdio, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     process::{ChildStdout, Command},
 };
-
 use warp::{
-    http,
+    http::{self, StatusCode},
     hyper::{body::Sender, Body, Response},
-    Rejection,
 };
 
-use crate::errors::FreighterError;
+use crate::{config::GitBackend, server::upload_pack::BuiltinUploadPack};
+
+/// the parts of a smart http request which `git http-backend` needs
+#[derive(Debug, Clone, Default)]
+pub struct GitRequest {
+    pub method: http::Method,
+    pub query: String,
+    pub content_type: Option<String>,
+    // git client may gzip the request body of upload-pack
+    pub content_encoding: Option<String>,
+    // the `Git-Protocol` header, like `version=2`
+    pub git_protocol: Option<String>,
+}
+
+/// error of git requests, it's returned to the client with the status code
+#[derive(Debug)]
+pub struct GitError {
+    pub status: StatusCode,
+    pub error: anyhow::Error,
+}
 
-#[derive(Default)]
-pub struct GitCommand {}
+impl GitError {
+    pub fn new(status: StatusCode, error: anyhow::Error) -> GitError {
+        GitError { status, error }
+    }
+
+    pub fn bad_request(detail: impl ToString) -> GitError {
+        GitError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(detail.to_string()))
+    }
+}
+
+impl From<anyhow::Error> for GitError {
+    fn from(err: anyhow::Error) -> GitError {
+        GitError::new(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
 
+impl From<std::io::Error> for GitError {
+    fn from(err: std::io::Error) -> GitError {
+        GitError::new(StatusCode::INTERNAL_SERVER_ERROR, err.into())
+    }
+}
+
+impl From<git2::Error> for GitError {
+    fn from(err: git2::Error) -> GitError {
+        let status = match err.code() {
+            git2::ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        GitError::new(status, err.into())
+    }
+}
+
+/// serve the index repository to git clients
+#[async_trait]
+pub trait UploadPack: Send + Sync {
+    /// GET /info/refs?service=git-upload-pack, the refs advertisement
+    async fn info_refs(
+        &self,
+        body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError>;
+
+    /// POST /git-upload-pack, the negotiation and packfile
+    async fn upload_pack(
+        &self,
+        body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError>;
+}
+
+/// choose the upload-pack implementation by config
+pub fn upload_pack_backend(backend: GitBackend) -> Arc<dyn UploadPack> {
+    let use_git = match backend {
+        GitBackend::Git => true,
+        GitBackend::Builtin => false,
+        GitBackend::Auto => std::process::Command::new("git")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success()),
+    };
+    if use_git {
+        Arc::new(GitCommand::default())
+    } else {
+        Arc::new(BuiltinUploadPack::default())
+    }
+}
+
+/// spawn `git http-backend` for each request
+///
 /// ### References Codes
 ///
 /// - [conduit-git-http-backend][<https://github.com/conduit-rust/conduit-git-http-backend/blob/master/src/lib.rs>].
 ///
-///
-/// handle request from git client
-impl GitCommand {
-    pub async fn git_info_refs(
-        &self,
-        mut body: impl Buf,
-        work_dir: PathBuf,
-    ) -> Result<Response<Body>, Rejection> {
-        let mut cmd = Command::new("git");
-        // git 数据检查
-        cmd.args([
-            "upload-pack",
-            // "--http-backend-info-refs",
-            "--stateless-rpc",
-            "--advertise-refs",
-            work_dir.join("crates.io-index").to_str().unwrap(),
-        ]);
-        cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
-
-        let p = cmd.spawn().unwrap();
-        let mut git_input = p.stdin.unwrap();
-
-        while body.has_remaining() {
-            git_input.write_all_buf(&mut body.chunk()).await.unwrap();
-
-            let cnt = body.chunk().len();
-            body.advance(cnt);
-        }
-        let git_output = BufReader::new(p.stdout.unwrap());
-        let mut headers = HashMap::new();
-        headers.insert(
-            "Content-Type".to_string(),
-            "application/x-git-upload-pack-advertisement".to_string(),
-        );
-        headers.insert(
-            "Cache-Control".to_string(),
-            "no-cache, max-age=0, must-revalidate".to_string(),
-        );
-        tracing::info!("headers: {:?}", headers);
-        let mut resp = Response::builder();
-        for (key, val) in headers {
-            resp = resp.header(&key, val);
-        }
+#[derive(Default)]
+pub struct GitCommand {}
 
-        let (sender, body) = Body::channel();
-        tokio::spawn(send(sender, git_output, true));
+#[async_trait]
+impl UploadPack for GitCommand {
+    async fn info_refs(
+        &self,
+        body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError> {
+        self.http_backend(body, repo_path, "info/refs", request)
+            .await
+    }
 
-        let resp = resp.body(body).unwrap();
-        Ok(resp)
+    async fn upload_pack(
+        &self,
+        body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError> {
+        self.http_backend(body, repo_path, "git-upload-pack", request)
+            .await
     }
+}
 
-    pub async fn git_upload_pack(
+impl GitCommand {
+    /// run `git http-backend` as a cgi program, the protocol version is negotiated by
+    /// `GIT_PROTOCOL`, and shallow or partial clone requests are passed as they are
+    async fn http_backend(
         &self,
-        mut body: impl Buf,
-        work_dir: PathBuf,
-        method: http::Method,
-        content_type: Option<String>,
-    ) -> Result<Response<Body>, Rejection> {
+        body: Bytes,
+        repo_path: PathBuf,
+        service: &str,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError> {
+        // http-backend finds the repository by the path under the project root
+        let (project_root, repo_name) = match (repo_path.parent(), repo_path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+            _ => return Err(anyhow::anyhow!("invalid repository path").into()),
+        };
         let mut cmd = Command::new("git");
         cmd.arg("http-backend");
-        cmd.env("GIT_PROJECT_ROOT", &work_dir);
-        cmd.env("PATH_INFO", "/crates.io-index/git-upload-pack");
-        cmd.env("REQUEST_METHOD", method.as_str());
-        // cmd.env("QUERY_STRING", query);
-        if let Some(content_type) = content_type {
+        cmd.env("GIT_PROJECT_ROOT", project_root);
+        cmd.env("PATH_INFO", format!("/{}/{}", repo_name, service));
+        cmd.env("REQUEST_METHOD", request.method.as_str());
+        cmd.env("QUERY_STRING", &request.query);
+        cmd.env("CONTENT_LENGTH", body.len().to_string());
+        if let Some(content_type) = request.content_type {
             cmd.env("CONTENT_TYPE", content_type);
         }
+        if let Some(content_encoding) = request.content_encoding {
+            cmd.env("HTTP_CONTENT_ENCODING", content_encoding);
+        }
+        if let Some(git_protocol) = request.git_protocol {
+            cmd.env("HTTP_GIT_PROTOCOL", &git_protocol);
+            cmd.env("GIT_PROTOCOL", git_protocol);
+        }
+        // allow `--filter` requests of partial clone
+        cmd.env("GIT_CONFIG_COUNT", "1");
+        cmd.env("GIT_CONFIG_KEY_0", "uploadpack.allowFilter");
+        cmd.env("GIT_CONFIG_VALUE_0", "true");
         cmd.env("GIT_HTTP_EXPORT_ALL", "true");
         cmd.stderr(Stdio::inherit());
         cmd.stdout(Stdio::piped());
         cmd.stdin(Stdio::piped());
 
-        let p = cmd.spawn().unwrap();
-        let mut git_input = p.stdin.unwrap();
-
-        while body.has_remaining() {
-            git_input.write_all_buf(&mut body.chunk()).await.unwrap();
-
-            let cnt = body.chunk().len();
-            println!(
-                "request body: {:?}",
-                String::from_utf8(body.chunk().to_vec()).unwrap()
-            );
-            body.advance(cnt);
-        }
-
-        let mut git_output = BufReader::new(p.stdout.unwrap());
+        let mut p = cmd.spawn().map_err(|err| match err.kind() {
+            ErrorKind::NotFound => GitError::new(
+                StatusCode::NOT_IMPLEMENTED,
+                anyhow::anyhow!("git is not installed, set `git_backend` to builtin"),
+            ),
+            _ => err.into(),
+        })?;
+        let (mut git_input, git_output) = match (p.stdin.take(), p.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => return Err(anyhow::anyhow!("failed to open pipes of git").into()),
+        };
+
+        // write in another task, git may start to respond before the whole body is read,
+        // and stdin is closed when it's done so git knows the end of the request
+        tokio::spawn(async move {
+            if let Err(err) = git_input.write_all(&body).await {
+                tracing::error!("failed to write request to git: {:?}", err);
+            }
+        });
 
-        let mut headers = HashMap::new();
+        let mut git_output = BufReader::new(git_output);
+        let mut resp = Response::builder();
         loop {
             let mut line = String::new();
-            git_output.read_line(&mut line).await.unwrap();
+            git_output.read_line(&mut line).await?;
             let line = line.trim_end();
             if line.is_empty() {
                 break;
             }
             if let Some((key, value)) = line.split_once(": ") {
-                headers.insert(key.to_string(), value.to_string());
+                // cgi uses `Status` header for the response status
+                if key.eq_ignore_ascii_case("Status") {
+                    let code = value.split(' ').next().unwrap_or_default();
+                    resp = resp.status(code.parse().unwrap_or(StatusCode::OK));
+                } else {
+                    resp = resp.header(key, value);
+                }
             }
         }
-        tracing::info!("headers: {:?}", headers);
-        let mut resp = Response::builder();
-        for (key, val) in headers {
-            resp = resp.header(&key, val);
-        }
+        tracing::debug!("headers: {:?}", resp.headers_ref());
 
         let (sender, body) = Body::channel();
-        tokio::spawn(send(sender, git_output, false));
-        let resp = resp.body(body).unwrap();
-        Ok(resp)
+        tokio::spawn(send(sender, git_output));
+        resp.body(body)
+            .map_err(|err| anyhow::Error::from(err).into())
     }
 }
 
-async fn send(
-    mut sender: Sender,
-    mut git_output: BufReader<ChildStdout>,
-    add_refs: bool,
-) -> Result<(), FreighterError> {
-    if add_refs {
-        let mut buf = BytesMut::new();
-        buf.put(&b"001e# service=git-upload-pack\n0000"[..]);
-        sender.send_data(buf.freeze()).await.unwrap();
-    }
-
+async fn send(mut sender: Sender, mut git_output: BufReader<ChildStdout>) {
     loop {
         let mut bytes_out = BytesMut::new();
-        let res = git_output.read_buf(&mut bytes_out).await;
-        tracing::info!("reading output :{:?}", res);
-        if bytes_out.is_empty() {
-            tracing::info!("send:empty");
-            return Ok(());
-        }
-        if add_refs {
-            tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
+        match git_output.read_buf(&mut bytes_out).await {
+            Ok(0) => return,
+            Ok(_) => {
+                if sender.send_data(bytes_out.freeze()).await.is_err() {
+                    // the client is gone
+                    return;
+                }
+            }
+            Err(err) => {
+                tracing::error!("failed to read output of git: {:?}", err);
+                sender.abort();
+                return;
+            }
         }
-        sender.send_data(bytes_out.freeze()).await.unwrap();
     }
 }
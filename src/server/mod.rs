@@ -5,6 +5,10 @@
 //!
 //!
 
+pub mod cache;
 pub mod file_server;
 pub mod git_protocol;
 mod model;
+pub mod owners;
+pub mod tokens;
+pub mod upload_pack;
@@ -1,7 +1,23 @@
 use std::collections::BTreeMap;
 
+use chrono::{SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    config::RegistryMode,
+    handler::{
+        crates_file::{Dependency, DependencyKind, IndexFile},
+        index::Snapshot,
+        search::SearchEntry,
+    },
+};
+
+// dependencies from crates.io are published with this registry url
+const CRATES_IO_INDEX: &[&str] = &[
+    "https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CratesPublish {
     // List of strings of the authors.
@@ -17,10 +33,10 @@ pub struct CratesPublish {
     pub deps: Vec<Dep>,
     // Description field from the manifest.
     // May be null. crates.io requires at least some content.
-    pub description: String,
+    pub description: Option<String>,
     // String of the URL to the website for this package's documentation.
     // May be null.
-    pub documentation: String,
+    pub documentation: Option<String>,
     // Set of features defined for the package.
     // Each feature maps to an array of features or dependencies it enables.
     // Cargo does not impose limitations on feature names, but crates.io
@@ -28,12 +44,12 @@ pub struct CratesPublish {
     pub features: BTreeMap<String, Vec<String>>,
     // String of the URL to the website for this package's home page.
     // May be null.
-    pub homepage: String,
+    pub homepage: Option<String>,
     // Array of strings of keywords for the package.
     pub keywords: Vec<String>,
     // String of the license for the package.
     // May be null. crates.io requires either `license` or `license_file` to be set.
-    pub license: String,
+    pub license: Option<String>,
     // String of a relative path to a license file in the crate.
     // May be null.
     pub license_file: Option<String>,
@@ -43,15 +59,19 @@ pub struct CratesPublish {
     pub name: String,
     // String of the content of the README file.
     // May be null.
-    pub readme: String,
+    pub readme: Option<String>,
     // String of a relative path to a README file in the crate.
     // May be null.
-    pub readme_file: String,
+    pub readme_file: Option<String>,
     // String of the URL to the website for the source repository of this package.
     // May be null.
-    pub repository: String,
+    pub repository: Option<String>,
     // The version of the package being published.
     pub vers: String,
+    // The minimum supported Rust version of the package.
+    // May be null.
+    #[serde(default)]
+    pub rust_version: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -73,7 +93,7 @@ pub struct Dep {
     // The URL of the index of the registry where this dependency is
     // from as a string. If not specified or null, it is assumed the
     // dependency is in the current registry.
-    pub registry: String,
+    pub registry: Option<String>,
     // The target platform for the dependency.
     // null if not a target dependency.
     // Otherwise, a string such as "cfg(windows)".
@@ -83,9 +103,77 @@ pub struct Dep {
     // If the dependency is renamed, this is a string of the new
     // package name. If not specified or null, this dependency is not
     // renamed.
+    #[serde(default)]
     pub explicit_name_in_toml: Option<String>,
 }
 
+impl CratesPublish {
+    /// convert the publish metadata to a line of index file, see the json schema of
+    /// [registry index](https://doc.rust-lang.org/cargo/reference/registry-index.html#json-schema)
+    pub fn to_index_file(&self, cksum: String, mode: RegistryMode) -> IndexFile {
+        // features use the `dep:` or `pkg?/feature` syntax are saved in `features2`,
+        // so that older versions of cargo can still parse the index
+        let (features2, features): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            self.features.clone().into_iter().partition(|(_, values)| {
+                values
+                    .iter()
+                    .any(|v| v.starts_with("dep:") || v.contains("?/"))
+            });
+        let features2 = (!features2.is_empty()).then_some(features2);
+        IndexFile {
+            name: self.name.clone(),
+            vers: self.vers.clone(),
+            deps: self
+                .deps
+                .iter()
+                .map(|dep| dep.to_dependency(mode))
+                .collect(),
+            cksum: Some(cksum),
+            features,
+            v: features2.as_ref().map(|_| 2),
+            features2,
+            yanked: Some(false),
+            links: self.links.clone(),
+            rust_version: self.rust_version.clone(),
+            // the same format as crates.io, like `2024-01-01T00:00:00Z`
+            pubtime: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)),
+        }
+    }
+}
+
+impl Dep {
+    /// convert the dependency to the one in index file, `mode` is the registry it's published to
+    pub fn to_dependency(&self, mode: RegistryMode) -> Dependency {
+        // the index uses the name in Cargo.toml, and the original crate name of a
+        // renamed dependency is saved in `package`
+        let (name, package) = match &self.explicit_name_in_toml {
+            Some(rename) => (rename.clone(), Some(self.name.clone())),
+            None => (self.name.clone(), None),
+        };
+        let kind = match self.kind.as_str() {
+            "dev" => DependencyKind::Dev,
+            "build" => DependencyKind::Build,
+            _ => DependencyKind::Normal,
+        };
+        // the index of the crates.io mirror has the crates of crates.io, so dependencies
+        // from crates.io are in the current registry, but a private registry doesn't
+        let registry = self.registry.clone().filter(|registry| {
+            mode == RegistryMode::Private || !CRATES_IO_INDEX.contains(&registry.as_str())
+        });
+        Dependency {
+            name,
+            req: self.version_req.clone(),
+            features: self.features.clone(),
+            optional: self.optional,
+            default_features: self.default_features,
+            target: self.target.clone(),
+            kind: Some(kind),
+            registry,
+            package,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Badge {}
 
@@ -105,6 +193,76 @@ pub struct Warning {
     pub other: Vec<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct YankRsp {
+    // Indicates the yank or unyank operation succeeded, always true.
+    pub ok: bool,
+}
+
+impl Default for YankRsp {
+    fn default() -> Self {
+        YankRsp { ok: true }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnersReq {
+    // Array of `login` strings of owners to add or remove.
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OwnersRsp {
+    // Array of owners of the crate.
+    pub users: Vec<User>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct User {
+    // Unique unsigned 32-bit integer of the owner.
+    pub id: u32,
+    // The unique username of the owner.
+    pub login: String,
+    // Name of the owner.
+    // This is optional and may be null.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OwnersEditRsp {
+    // Indicates the add or remove operation succeeded, always true.
+    pub ok: bool,
+    // A string to be displayed to the user.
+    pub msg: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchQuery {
+    // The search query string.
+    pub q: String,
+    // Number of results, default is 10, max is 100.
+    pub per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SearchRsp {
+    // Array of results.
+    pub crates: Vec<SearchEntry>,
+    pub meta: SearchMeta,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SearchMeta {
+    // Total number of results available on the server.
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SnapshotsRsp {
+    // Synced commits of the index, the oldest first.
+    pub snapshots: Vec<Snapshot>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Errors {
     // Array of errors to display to the user.
@@ -124,3 +282,64 @@ pub struct ErrorDetail {
     // The error message as a string.
     pub detail: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{config::RegistryMode, handler::crates_file::DependencyKind};
+
+    use super::CratesPublish;
+
+    // metadata in the shape `cargo publish` sends
+    fn load_fixture(name: &str) -> CratesPublish {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("data/tests/fixtures/publish")
+            .join(name);
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_publish_to_index_file() {
+        let publish = load_fixture("freighter-demo-0.1.0.json");
+        let index_file = publish.to_index_file("abc".to_owned(), RegistryMode::Mirror);
+        assert_eq!(index_file.name, "freighter-demo");
+        assert_eq!(index_file.cksum.as_deref(), Some("abc"));
+        assert_eq!(index_file.yanked, Some(false));
+
+        // renamed dependency uses the name in Cargo.toml and keeps the crate name in package
+        let json = index_file.deps.iter().find(|d| d.name == "json").unwrap();
+        assert_eq!(json.package.as_deref(), Some("serde_json"));
+        assert!(json.optional);
+        for dep in &index_file.deps {
+            assert_eq!(dep.registry, None);
+        }
+
+        let kind = |name: &str| {
+            let dep = index_file.deps.iter().find(|d| d.name == name).unwrap();
+            (dep.kind.unwrap(), dep.target.clone())
+        };
+        assert_eq!(kind("rand").0, DependencyKind::Dev);
+        assert_eq!(kind("cc").0, DependencyKind::Build);
+        assert_eq!(kind("serde").0, DependencyKind::Normal);
+        assert_eq!(kind("winapi").1.as_deref(), Some("cfg(windows)"));
+
+        // `dep:` features are moved to features2
+        assert_eq!(index_file.v, Some(2));
+        assert!(index_file.features2.as_ref().unwrap().contains_key("json"));
+        assert!(!index_file.features.contains_key("json"));
+        assert!(index_file.features.contains_key("std"));
+
+        let line = serde_json::to_string(&index_file).unwrap();
+        assert!(!line.contains("\"registry\""));
+
+        // a private registry doesn't have the crates of crates.io
+        let index_file = publish.to_index_file("abc".to_owned(), RegistryMode::Private);
+        for dep in &index_file.deps {
+            assert_eq!(
+                dep.registry.as_deref(),
+                Some("https://github.com/rust-lang/crates.io-index")
+            );
+        }
+    }
+}
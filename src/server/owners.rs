@@ -0,0 +1,98 @@
+//! persist the owners of crates published to the local registry, each crate keeps
+//! a json file with the login of its owners under `owners_path`, the layout of the
+//! files follows the rules of crates index file.
+//!
+//!
+//!
+
+use std::{fs, io::ErrorKind, path::PathBuf, sync::Mutex};
+
+use anyhow::anyhow;
+
+use crate::handler::utils;
+
+// serialize all read-modify-write operations on owners files
+static OWNERS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone)]
+pub struct OwnerStore {
+    pub path: PathBuf,
+}
+
+impl OwnerStore {
+    pub fn new(path: PathBuf) -> Self {
+        OwnerStore { path }
+    }
+
+    fn owners_file(&self, name: &str) -> PathBuf {
+        let suffix = utils::index_suffix(&name.to_lowercase());
+        self.path.join(format!("{}.json", suffix))
+    }
+
+    /// return the owners of the crate, empty if the crate has no owner record
+    pub fn list(&self, name: &str) -> Result<Vec<String>, anyhow::Error> {
+        match fs::read_to_string(self.owners_file(name)) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// add logins to the owners of the crate, existing owners are ignored
+    pub fn add(&self, name: &str, logins: &[String]) -> Result<(), anyhow::Error> {
+        let _guard = OWNERS_LOCK.lock().unwrap();
+        let mut owners = self.list(name)?;
+        for login in logins {
+            if !owners.contains(login) {
+                owners.push(login.to_owned());
+            }
+        }
+        self.save(name, &owners)
+    }
+
+    /// remove logins from the owners of the crate, a crate can't be left without owner
+    pub fn remove(&self, name: &str, logins: &[String]) -> Result<(), anyhow::Error> {
+        let _guard = OWNERS_LOCK.lock().unwrap();
+        let mut owners = self.list(name)?;
+        owners.retain(|owner| !logins.contains(owner));
+        if owners.is_empty() {
+            return Err(anyhow!("cannot remove all owners of crate `{}`", name));
+        }
+        self.save(name, &owners)
+    }
+
+    fn save(&self, name: &str, owners: &[String]) -> Result<(), anyhow::Error> {
+        let owners_file = self.owners_file(name);
+        if let Some(parent) = owners_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(owners_file, serde_json::to_string(owners)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::OwnerStore;
+
+    #[test]
+    fn test_owners() {
+        let path = env::temp_dir().join("freighter-test-owners");
+        let _ = fs::remove_dir_all(&path);
+        let store = OwnerStore::new(path.clone());
+
+        assert!(store.list("foo").unwrap().is_empty());
+        store.add("foo", &["alice".to_owned()]).unwrap();
+        store
+            .add("Foo", &["alice".to_owned(), "bob".to_owned()])
+            .unwrap();
+        assert_eq!(store.list("foo").unwrap(), vec!["alice", "bob"]);
+
+        store.remove("foo", &["alice".to_owned()]).unwrap();
+        assert_eq!(store.list("foo").unwrap(), vec!["bob"]);
+        assert!(store.remove("foo", &["bob".to_owned()]).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
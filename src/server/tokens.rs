@@ -0,0 +1,127 @@
+//! persist the api tokens used by cargo to publish, yank and manage owners of crates,
+//! only the sha256 of a token is saved in `tokens_path/tokens.json`, the token itself
+//! is shown once when it's created.
+//!
+//!
+//!
+
+use std::{fs, io::ErrorKind, path::PathBuf, sync::Mutex};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// serialize all read-modify-write operations on tokens file
+static TOKENS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenRecord {
+    pub id: u32,
+    // the user this token belongs to
+    pub login: String,
+    // a description of where the token is used
+    pub name: String,
+    pub token_hash: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenStore {
+    pub path: PathBuf,
+}
+
+impl TokenStore {
+    pub fn new(path: PathBuf) -> Self {
+        TokenStore { path }
+    }
+
+    fn tokens_file(&self) -> PathBuf {
+        self.path.join("tokens.json")
+    }
+
+    pub fn list(&self) -> Result<Vec<TokenRecord>, anyhow::Error> {
+        match fs::read_to_string(self.tokens_file()) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// generate a new token for the login and return it
+    pub fn create(&self, login: &str, name: &str) -> Result<String, anyhow::Error> {
+        let _guard = TOKENS_LOCK.lock().unwrap();
+        let mut records = self.list()?;
+        let mut bytes = [0u8; 32];
+        openssl::rand::rand_bytes(&mut bytes)?;
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        records.push(TokenRecord {
+            id: records.iter().map(|r| r.id).max().unwrap_or(0) + 1,
+            login: login.to_owned(),
+            name: name.to_owned(),
+            token_hash: hash_token(&token),
+            created_at: Utc::now().to_rfc3339(),
+        });
+        self.save(&records)?;
+        Ok(token)
+    }
+
+    pub fn revoke(&self, id: u32) -> Result<(), anyhow::Error> {
+        let _guard = TOKENS_LOCK.lock().unwrap();
+        let mut records = self.list()?;
+        let count = records.len();
+        records.retain(|r| r.id != id);
+        if records.len() == count {
+            return Err(anyhow!("token with id {} does not exist", id));
+        }
+        self.save(&records)
+    }
+
+    /// find the login of the given token, return None if the token is invalid
+    pub fn login(&self, token: &str) -> Result<Option<String>, anyhow::Error> {
+        let token_hash = hash_token(token);
+        Ok(self
+            .list()?
+            .into_iter()
+            .find(|r| r.token_hash == token_hash)
+            .map(|r| r.login))
+    }
+
+    fn save(&self, records: &[TokenRecord]) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.tokens_file(), serde_json::to_string_pretty(records)?)?;
+        Ok(())
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::TokenStore;
+
+    #[test]
+    fn test_tokens() {
+        let path = env::temp_dir().join("freighter-test-tokens");
+        let _ = fs::remove_dir_all(&path);
+        let store = TokenStore::new(path.clone());
+
+        let token = store.create("alice", "ci").unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(store.login(&token).unwrap(), Some("alice".to_owned()));
+        assert_eq!(store.login("invalid").unwrap(), None);
+
+        let id = store.list().unwrap()[0].id;
+        store.revoke(id).unwrap();
+        assert_eq!(store.login(&token).unwrap(), None);
+        assert!(store.revoke(id).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,635 @@
+//! builtin upload-pack on top of libgit2, it speaks the stateless http protocol v0 which is
+//! what clients fall back to when v2 is not offered, and supports shallow clone with `--depth`.
+//!
+//! Each POST of the client is a whole negotiation round: the wants, the shallow commits and
+//! depth it has, then a flush and the haves. The round ends with `done` when the client is
+//! ready for the packfile.
+//!
+//! ### References
+//!
+//! - [pack-protocol](<https://git-scm.com/docs/pack-protocol>)
+//! - [http-protocol](<https://git-scm.com/docs/http-protocol>)
+//!
+
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use git2::{ObjectType, Oid, Repository};
+use tokio::runtime::Handle;
+use warp::{
+    http::{header, Method, StatusCode},
+    hyper::{body::Sender, Body, Response},
+};
+
+use super::git_protocol::{GitError, GitRequest, UploadPack};
+
+// max data size of a pkt-line with side-band-64k, without the length and band byte
+const LARGE_PACKET_DATA_MAX: usize = 65515;
+// max data size of a pkt-line with side-band
+const SMALL_PACKET_DATA_MAX: usize = 995;
+
+#[derive(Default)]
+pub struct BuiltinUploadPack {}
+
+#[async_trait]
+impl UploadPack for BuiltinUploadPack {
+    async fn info_refs(
+        &self,
+        _body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError> {
+        if request.method != Method::GET {
+            return Err(GitError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                anyhow::anyhow!("method not allowed"),
+            ));
+        }
+        // the dumb protocol is not supported
+        if !request
+            .query
+            .split('&')
+            .any(|p| p == "service=git-upload-pack")
+        {
+            return Err(GitError::new(
+                StatusCode::FORBIDDEN,
+                anyhow::anyhow!("only git-upload-pack service is supported"),
+            ));
+        }
+        let content = tokio::task::spawn_blocking(move || advertise_refs(&repo_path))
+            .await
+            .map_err(anyhow::Error::from)??;
+        Response::builder()
+            .header(
+                header::CONTENT_TYPE,
+                "application/x-git-upload-pack-advertisement",
+            )
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(content))
+            .map_err(|err| anyhow::Error::from(err).into())
+    }
+
+    async fn upload_pack(
+        &self,
+        body: Bytes,
+        repo_path: PathBuf,
+        request: GitRequest,
+    ) -> Result<Response<Body>, GitError> {
+        if request.method != Method::POST {
+            return Err(GitError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                anyhow::anyhow!("method not allowed"),
+            ));
+        }
+        let body = match request.content_encoding.as_deref() {
+            Some("gzip") | Some("x-gzip") => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(body.as_ref())
+                    .read_to_end(&mut decoded)
+                    .map_err(|err| GitError::bad_request(format!("bad gzip body: {}", err)))?;
+                Bytes::from(decoded)
+            }
+            _ => body,
+        };
+        let upload = UploadRequest::parse(&body)?;
+
+        let path = repo_path.clone();
+        let (head, plan) = tokio::task::spawn_blocking(move || negotiate(&path, upload))
+            .await
+            .map_err(anyhow::Error::from)??;
+
+        let (mut sender, body) = Body::channel();
+        let handle = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            if handle
+                .block_on(sender.send_data(Bytes::from(head)))
+                .is_err()
+            {
+                return;
+            }
+            if let Some(plan) = plan {
+                let mut output = PackOutput { sender, handle };
+                if let Err(err) = output.send_pack(&repo_path, plan) {
+                    tracing::error!("failed to send packfile: {:?}", err.error);
+                    output.sender.abort();
+                }
+            }
+        });
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/x-git-upload-pack-result")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(body)
+            .map_err(|err| anyhow::Error::from(err).into())
+    }
+}
+
+/// one round of negotiation sent by the client
+#[derive(Debug, Default, PartialEq)]
+struct UploadRequest {
+    wants: Vec<Oid>,
+    capabilities: Vec<String>,
+    // commits which are shallow in the client
+    shallows: Vec<Oid>,
+    depth: Option<usize>,
+    haves: Vec<Oid>,
+    // whether there are any lines after the wants
+    negotiating: bool,
+    done: bool,
+}
+
+impl UploadRequest {
+    fn parse(body: &[u8]) -> Result<UploadRequest, GitError> {
+        let mut req = UploadRequest::default();
+        let mut wants_done = false;
+        for line in PktLines(body) {
+            let line = match line? {
+                Some(line) => line,
+                None => {
+                    wants_done = true;
+                    continue;
+                }
+            };
+            let line = std::str::from_utf8(line)
+                .map_err(|_| GitError::bad_request("invalid pkt-line"))?
+                .trim_end_matches('\n');
+            let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+            if wants_done {
+                req.negotiating = true;
+            }
+            match (command, wants_done) {
+                ("want", false) => {
+                    let mut args = arg.split(' ');
+                    req.wants.push(parse_oid(args.next())?);
+                    if req.wants.len() == 1 {
+                        req.capabilities = args.map(|c| c.to_owned()).collect();
+                    }
+                }
+                ("shallow", false) => req.shallows.push(parse_oid(Some(arg))?),
+                ("deepen", false) => {
+                    let depth = arg
+                        .parse()
+                        .map_err(|_| GitError::bad_request(format!("invalid depth: {}", arg)))?;
+                    req.depth = Some(depth);
+                }
+                ("have", true) => req.haves.push(parse_oid(Some(arg))?),
+                ("done", true) => req.done = true,
+                _ => return Err(GitError::bad_request(format!("unexpected line: {}", line))),
+            }
+        }
+        if req.wants.is_empty() {
+            return Err(GitError::bad_request("no want lines"));
+        }
+        Ok(req)
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn side_band(&self) -> Option<usize> {
+        if self.has_capability("side-band-64k") {
+            Some(LARGE_PACKET_DATA_MAX)
+        } else if self.has_capability("side-band") {
+            Some(SMALL_PACKET_DATA_MAX)
+        } else {
+            None
+        }
+    }
+}
+
+// iterate the pkt-lines of the body, None is a flush-pkt
+struct PktLines<'a>(&'a [u8]);
+
+impl<'a> Iterator for PktLines<'a> {
+    type Item = Result<Option<&'a [u8]>, GitError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let len = match self
+            .0
+            .get(..4)
+            .and_then(|len| std::str::from_utf8(len).ok())
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+        {
+            Some(0) => {
+                self.0 = &self.0[4..];
+                return Some(Ok(None));
+            }
+            Some(len) if len >= 4 && len <= self.0.len() => len,
+            _ => {
+                self.0 = &[];
+                return Some(Err(GitError::bad_request("invalid pkt-line length")));
+            }
+        };
+        let line = &self.0[4..len];
+        self.0 = &self.0[len..];
+        Some(Ok(Some(line)))
+    }
+}
+
+fn parse_oid(hex: Option<&str>) -> Result<Oid, GitError> {
+    let hex = hex.unwrap_or_default();
+    match hex.len() {
+        40 => {
+            Oid::from_str(hex).map_err(|_| GitError::bad_request(format!("invalid oid: {}", hex)))
+        }
+        _ => Err(GitError::bad_request(format!("invalid oid: {}", hex))),
+    }
+}
+
+fn pkt_line(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn flush_pkt(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(b"0000");
+}
+
+fn advertise_refs(repo_path: &Path) -> Result<Vec<u8>, GitError> {
+    let repo = Repository::open(repo_path)?;
+    let mut capabilities = vec![
+        "multi_ack",
+        "multi_ack_detailed",
+        "side-band",
+        "side-band-64k",
+        "ofs-delta",
+        "shallow",
+        "no-progress",
+    ]
+    .into_iter()
+    .map(|c| c.to_owned())
+    .collect::<Vec<_>>();
+    if let Ok(head) = repo.find_reference("HEAD") {
+        if let Some(target) = head.symbolic_target() {
+            capabilities.push(format!("symref=HEAD:{}", target));
+        }
+    }
+    capabilities.push(format!("agent=freighter/{}", env!("CARGO_PKG_VERSION")));
+    let mut refs = served_refs(&repo)?;
+
+    let mut buf = Vec::new();
+    pkt_line(&mut buf, b"# service=git-upload-pack\n");
+    flush_pkt(&mut buf);
+    if refs.is_empty() {
+        refs.push((Oid::zero(), "capabilities^{}".to_owned()));
+    }
+    for (i, (oid, name)) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", oid, name, capabilities.join(" "))
+        } else {
+            format!("{} {}\n", oid, name)
+        };
+        pkt_line(&mut buf, line.as_bytes());
+    }
+    flush_pkt(&mut buf);
+    Ok(buf)
+}
+
+// HEAD, branches and tags with the peeled annotated tags, in the advertised order
+fn served_refs(repo: &Repository) -> Result<Vec<(Oid, String)>, GitError> {
+    let mut refs = Vec::new();
+    if let Ok(head) = repo.find_reference("HEAD") {
+        if let Ok(oid) = head.resolve().and_then(|r| r.peel(ObjectType::Any)) {
+            refs.push((oid.id(), "HEAD".to_owned()));
+        }
+    }
+
+    let mut names = Vec::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            // only branches and tags are served
+            if name.starts_with("refs/heads/") || name.starts_with("refs/tags/") {
+                names.push((name.to_owned(), oid));
+            }
+        }
+    }
+    names.sort();
+    for (name, oid) in names {
+        refs.push((oid, name.clone()));
+        // peeled annotated tag
+        if let Ok(tag) = repo.find_tag(oid) {
+            refs.push((tag.target_id(), format!("{}^{{}}", name)));
+        }
+    }
+    Ok(refs)
+}
+
+// a want must be an advertised object or a commit reachable from an advertised commit,
+// like git without `uploadpack.allowAnySHA1InWant`
+fn is_reachable(repo: &Repository, tips: &[Oid], want: Oid) -> bool {
+    tips.contains(&want)
+        || tips
+            .iter()
+            .any(|tip| repo.graph_descendant_of(*tip, want).unwrap_or(false))
+}
+
+/// objects to put into the packfile
+#[derive(Debug)]
+struct PackPlan {
+    side_band: Option<usize>,
+    // non-commit objects wanted, like annotated tags
+    objects: Vec<Oid>,
+    commits: PackCommits,
+}
+
+#[derive(Debug)]
+enum PackCommits {
+    // everything reachable from the wants but not from the haves
+    Walk { wants: Vec<Oid>, haves: Vec<Oid> },
+    // commits within the depth of a shallow clone
+    Shallow(Vec<Oid>),
+}
+
+// answer one round of negotiation, the packfile is sent only when the client is done
+fn negotiate(
+    repo_path: &Path,
+    upload: UploadRequest,
+) -> Result<(Vec<u8>, Option<PackPlan>), GitError> {
+    let repo = Repository::open(repo_path)?;
+    let odb = repo.odb()?;
+    let mut buf = Vec::new();
+
+    let tips: Vec<Oid> = served_refs(&repo)?
+        .into_iter()
+        .map(|(oid, _)| oid)
+        .collect();
+    let mut objects = Vec::new();
+    let mut want_commits = Vec::new();
+    for want in &upload.wants {
+        let object = repo
+            .find_object(*want, None)
+            .ok()
+            .filter(|_| is_reachable(&repo, &tips, *want))
+            .ok_or_else(|| GitError::bad_request(format!("not our ref {}", want)))?;
+        if object.kind() != Some(ObjectType::Commit) {
+            objects.push(*want);
+        }
+        let commit = object
+            .peel_to_commit()
+            .map_err(|_| GitError::bad_request(format!("not a commit {}", want)))?;
+        want_commits.push(commit.id());
+    }
+
+    let mut shallow_commits = None;
+    if let Some(depth) = upload.depth {
+        // the client has the history of a have unless it's shallow there
+        let complete: HashSet<Oid> = upload
+            .haves
+            .iter()
+            .filter(|oid| odb.exists(**oid) && !upload.shallows.contains(oid))
+            .copied()
+            .collect();
+        let (commits, boundary) = shallow_walk(&repo, &want_commits, depth, &complete)?;
+        for oid in &boundary {
+            if !upload.shallows.contains(oid) {
+                pkt_line(&mut buf, format!("shallow {}\n", oid).as_bytes());
+            }
+        }
+        for oid in &upload.shallows {
+            if commits.contains(oid) && !boundary.contains(oid) {
+                pkt_line(&mut buf, format!("unshallow {}\n", oid).as_bytes());
+            }
+        }
+        flush_pkt(&mut buf);
+        shallow_commits = Some(commits.into_iter().collect());
+    }
+
+    if !upload.negotiating {
+        return Ok((buf, None));
+    }
+
+    let ack_suffix = if upload.has_capability("multi_ack_detailed") {
+        Some(" common")
+    } else if upload.has_capability("multi_ack") {
+        Some(" continue")
+    } else {
+        None
+    };
+    let mut common = Vec::new();
+    for have in &upload.haves {
+        if !odb.exists(*have) {
+            continue;
+        }
+        common.push(*have);
+        match ack_suffix {
+            Some(suffix) => pkt_line(&mut buf, format!("ACK {}{}\n", have, suffix).as_bytes()),
+            // only the first common commit is acked without multi_ack
+            None if common.len() == 1 => pkt_line(&mut buf, format!("ACK {}\n", have).as_bytes()),
+            None => {}
+        }
+    }
+
+    if !upload.done {
+        if common.is_empty() || ack_suffix.is_some() {
+            pkt_line(&mut buf, b"NAK\n");
+        }
+        return Ok((buf, None));
+    }
+    match common.last() {
+        Some(last) if ack_suffix.is_some() => {
+            pkt_line(&mut buf, format!("ACK {}\n", last).as_bytes())
+        }
+        Some(_) => {}
+        None => pkt_line(&mut buf, b"NAK\n"),
+    }
+
+    let commits = match shallow_commits {
+        Some(commits) => PackCommits::Shallow(commits),
+        None => PackCommits::Walk {
+            wants: want_commits,
+            haves: common,
+        },
+    };
+    let plan = PackPlan {
+        side_band: upload.side_band(),
+        objects,
+        commits,
+    };
+    Ok((buf, Some(plan)))
+}
+
+// find the commits within the depth from the wants, and the boundary commits which
+// become shallow in the client, the walk stops at the commits the client already has
+fn shallow_walk(
+    repo: &Repository,
+    wants: &[Oid],
+    depth: usize,
+    haves: &HashSet<Oid>,
+) -> Result<(HashSet<Oid>, Vec<Oid>), GitError> {
+    let mut commits = HashSet::new();
+    let mut boundary = Vec::new();
+    let mut queue = wants.iter().map(|oid| (*oid, 1)).collect::<VecDeque<_>>();
+    while let Some((oid, level)) = queue.pop_front() {
+        if haves.contains(&oid) || !commits.insert(oid) {
+            continue;
+        }
+        let commit = repo.find_commit(oid)?;
+        if level >= depth {
+            if commit.parent_count() > 0 {
+                boundary.push(oid);
+            }
+            continue;
+        }
+        queue.extend(commit.parent_ids().map(|parent| (parent, level + 1)));
+    }
+    Ok((commits, boundary))
+}
+
+// write packfile into the response body from a blocking thread
+struct PackOutput {
+    sender: Sender,
+    handle: Handle,
+}
+
+impl PackOutput {
+    fn send(&mut self, data: Vec<u8>) -> bool {
+        self.handle
+            .block_on(self.sender.send_data(Bytes::from(data)))
+            .is_ok()
+    }
+
+    fn send_pack(&mut self, repo_path: &Path, plan: PackPlan) -> Result<(), GitError> {
+        let repo = Repository::open(repo_path)?;
+        let mut builder = repo.packbuilder()?;
+        for oid in &plan.objects {
+            builder.insert_object(*oid, None)?;
+        }
+        match &plan.commits {
+            PackCommits::Walk { wants, haves } => {
+                let mut walk = repo.revwalk()?;
+                for oid in wants {
+                    walk.push(*oid)?;
+                }
+                for oid in haves {
+                    // haves which are not commits are just ignored
+                    let _ = walk.hide(*oid);
+                }
+                builder.insert_walk(&mut walk)?;
+            }
+            PackCommits::Shallow(commits) => {
+                for oid in commits {
+                    builder.insert_commit(*oid)?;
+                }
+            }
+        }
+
+        let side_band = plan.side_band;
+        let mut sent = true;
+        builder.foreach(|chunk| {
+            sent = match side_band {
+                Some(max) => chunk.chunks(max).all(|part| {
+                    let mut buf = Vec::with_capacity(part.len() + 5);
+                    // band 1 carries the packfile
+                    pkt_line(&mut buf, &[&[1], part].concat());
+                    self.send(buf)
+                }),
+                None => self.send(chunk.to_vec()),
+            };
+            sent
+        })?;
+        if sent && side_band.is_some() {
+            let mut buf = Vec::new();
+            flush_pkt(&mut buf);
+            self.send(buf);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use git2::{Oid, Repository, Signature};
+
+    use super::{flush_pkt, negotiate, pkt_line, PackCommits, UploadRequest};
+
+    // commit an empty tree on top of the parent, HEAD is moved only if `update_head`
+    fn commit(repo: &Repository, parent: Option<Oid>, message: &str, update_head: bool) -> Oid {
+        let sig = Signature::now("freighter", "freighter@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let parents: Vec<_> = parent
+            .map(|p| repo.find_commit(p).unwrap())
+            .into_iter()
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        let update_ref = if update_head { Some("HEAD") } else { None };
+        repo.commit(update_ref, &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let repo_path = env::temp_dir().join("freighter-test-upload-pack");
+        let _ = fs::remove_dir_all(&repo_path);
+        let repo = Repository::init(&repo_path).unwrap();
+        let first = commit(&repo, None, "first", true);
+        let second = commit(&repo, Some(first), "second", true);
+        let third = commit(&repo, Some(second), "third", true);
+        let dangling = commit(&repo, Some(third), "dangling", false);
+
+        let request = |wants: Vec<Oid>, haves: Vec<Oid>| UploadRequest {
+            wants,
+            depth: Some(10),
+            haves,
+            negotiating: true,
+            done: true,
+            ..Default::default()
+        };
+        // objects not reachable from the advertised refs are not served
+        assert!(negotiate(&repo_path, request(vec![dangling], Vec::new())).is_err());
+        assert!(negotiate(&repo_path, request(vec![second], Vec::new())).is_ok());
+
+        // a shallow fetch stops at the commits the client has
+        let (_, plan) = negotiate(&repo_path, request(vec![third], vec![first])).unwrap();
+        match plan.unwrap().commits {
+            PackCommits::Shallow(mut commits) => {
+                commits.sort();
+                let mut expected = vec![second, third];
+                expected.sort();
+                assert_eq!(commits, expected);
+            }
+            commits => panic!("unexpected commits {:?}", commits),
+        }
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_upload_request() {
+        let want = "4cbd5559ff9ab9916b0f3430e7da46ddfb2a2f67";
+        let have = "c05558524443c3d26135fcd235bf4af1f2a1a93a";
+        let mut body = Vec::new();
+        pkt_line(
+            &mut body,
+            format!("want {} multi_ack_detailed side-band-64k\n", want).as_bytes(),
+        );
+        pkt_line(&mut body, b"deepen 1\n");
+        flush_pkt(&mut body);
+        let req = UploadRequest::parse(&body).unwrap();
+        assert_eq!(req.wants, vec![Oid::from_str(want).unwrap()]);
+        assert_eq!(req.depth, Some(1));
+        assert_eq!(req.side_band(), Some(65515));
+        assert!(!req.negotiating);
+
+        pkt_line(&mut body, format!("have {}\n", have).as_bytes());
+        pkt_line(&mut body, b"done\n");
+        let req = UploadRequest::parse(&body).unwrap();
+        assert_eq!(req.haves, vec![Oid::from_str(have).unwrap()]);
+        assert!(req.negotiating && req.done);
+
+        assert!(UploadRequest::parse(b"0009done\n0000").is_err());
+        assert!(UploadRequest::parse(b"00ffwant").is_err());
+    }
+}